@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+
+use anyhow::Result;
+use appload_client::BackendReplier;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::orientation::current_orientation;
+use crate::settings::{parse_settings_file, CONFIG_DIRECTORY};
+use crate::MyBackend;
+
+const CALIBRATION_FILE: &str = "/home/root/.config/rmstream/calibration.conf";
+
+/// A position in the 0.0 - 1.0 range.
+type Point = (f64, f64);
+
+/// Where the user is asked to tap during calibration, in screen coordinates (0.0 - 1.0).
+const CALIBRATION_TARGETS: [Point; 5] =
+    [(0.1, 0.1), (0.9, 0.1), (0.5, 0.5), (0.1, 0.9), (0.9, 0.9)];
+
+/// Messages sent to the frontend during calibration.
+const MSG_CALIBRATION_TARGET: u32 = 4;
+const MSG_CALIBRATION_RESULT: u32 = 5;
+
+/// A 2D affine transform: `x' = a*x + b*y + c`, `y' = d*x + e*y + f`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub struct Affine(pub [f64; 6]);
+
+impl Affine {
+    pub const IDENTITY: Affine = Affine([1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+
+    pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        let [a, b, c, d, e, f] = self.0;
+        (a * x + b * y + c, d * x + e * y + f)
+    }
+
+    /// Applies the transform without the translation. Used for direction vectors like the tilt.
+    pub fn apply_linear(&self, x: f64, y: f64) -> (f64, f64) {
+        let [a, b, _, d, e, _] = self.0;
+        (a * x + b * y, d * x + e * y)
+    }
+
+    /// Returns the transform undoing this one, or `None` if it collapses the plane onto a line.
+    pub fn inverse(&self) -> Option<Affine> {
+        let [a, b, c, d, e, f] = self.0;
+        let determinant = a * e - b * d;
+        if determinant.abs() < 1e-12 {
+            return None;
+        }
+        let (ia, ib, id, ie) = (
+            e / determinant,
+            -b / determinant,
+            -d / determinant,
+            a / determinant,
+        );
+        Some(Affine([
+            ia,
+            ib,
+            -(ia * c + ib * f),
+            id,
+            ie,
+            -(id * c + ie * f),
+        ]))
+    }
+
+    /// Least-squares fit of the transform mapping each `from` point onto its `to` point.
+    /// Needs at least 3 points which aren't all on one line.
+    pub fn fit(points: &[(Point, Point)]) -> Option<Affine> {
+        let mut normal = [[0.0; 3]; 3];
+        let mut rhs_x = [0.0; 3];
+        let mut rhs_y = [0.0; 3];
+        for ((x, y), (to_x, to_y)) in points {
+            let row = [*x, *y, 1.0];
+            for i in 0..3 {
+                for j in 0..3 {
+                    normal[i][j] += row[i] * row[j];
+                }
+                rhs_x[i] += row[i] * to_x;
+                rhs_y[i] += row[i] * to_y;
+            }
+        }
+        let [a, b, c] = solve3(normal, rhs_x)?;
+        let [d, e, f] = solve3(normal, rhs_y)?;
+        Some(Affine([a, b, c, d, e, f]))
+    }
+}
+
+/// Solves `m * x = v` using Cramer's rule.
+fn solve3(m: [[f64; 3]; 3], v: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let determinant = det(m);
+    if determinant.abs() < 1e-12 {
+        return None;
+    }
+    let mut solution = [0.0; 3];
+    for (column, value) in solution.iter_mut().enumerate() {
+        let mut replaced = m;
+        for row in 0..3 {
+            replaced[row][column] = v[row];
+        }
+        *value = det(replaced) / determinant;
+    }
+    Some(solution)
+}
+
+impl Display for Affine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a},{b},{c},{d},{e},{g}")
+    }
+}
+
+impl FromStr for Affine {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|e| e.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| ())?;
+        Ok(Affine(values.try_into().map_err(|_| ())?))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CalibrationInput {
+    Pen,
+    Touch,
+}
+
+impl CalibrationInput {
+    fn name(self) -> &'static str {
+        match self {
+            CalibrationInput::Pen => "pen",
+            CalibrationInput::Touch => "touch",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [CalibrationInput::Pen, CalibrationInput::Touch]
+            .into_iter()
+            .find(|e| e.name() == name)
+    }
+}
+
+struct CalibrationRun {
+    input: CalibrationInput,
+    /// Raw positions recorded for the targets so far.
+    samples: Vec<Point>,
+    replier: BackendReplier<MyBackend>,
+}
+
+impl CalibrationRun {
+    fn request_next_target(&self) {
+        let (x, y) = CALIBRATION_TARGETS[self.samples.len()];
+        let _ = self
+            .replier
+            .send_message(MSG_CALIBRATION_TARGET, &format!("{x},{y}"));
+    }
+}
+
+lazy_static! {
+    /// Calibrated transforms, keyed by the input and the screen orientation in degrees.
+    static ref CALIBRATIONS: RwLock<HashMap<(CalibrationInput, u16), Affine>> =
+        RwLock::new(load_calibrations());
+    static ref CALIBRATION_RUN: Mutex<Option<CalibrationRun>> = Mutex::new(None);
+}
+
+fn load_calibrations() -> HashMap<(CalibrationInput, u16), Affine> {
+    let values = std::fs::read_to_string(CALIBRATION_FILE)
+        .map(|e| parse_settings_file(&e))
+        .unwrap_or_default();
+    values
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let (input, orientation) = key.split_once('.')?;
+            let key = (
+                CalibrationInput::from_name(input)?,
+                orientation.parse().ok()?,
+            );
+            match value.parse() {
+                Ok(transform) => Some((key, transform)),
+                Err(_) => {
+                    eprintln!("Invalid calibration for {key:?}. Ignoring it.");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn save_calibrations(calibrations: &HashMap<(CalibrationInput, u16), Affine>) -> Result<()> {
+    std::fs::create_dir_all(CONFIG_DIRECTORY)?;
+    let contents = calibrations
+        .iter()
+        .map(|((input, orientation), transform)| {
+            format!("{}.{} = {}\n", input.name(), orientation, transform)
+        })
+        .collect::<String>();
+    std::fs::write(CALIBRATION_FILE, contents)?;
+    Ok(())
+}
+
+/// Returns the calibrated transform for the current orientation, or `default` if there is none.
+pub fn get_transform(input: CalibrationInput, default: Affine) -> Affine {
+    CALIBRATIONS
+        .read()
+        .unwrap()
+        .get(&(input, current_orientation().degrees()))
+        .copied()
+        .unwrap_or(default)
+}
+
+pub fn start_calibration(input: CalibrationInput, replier: BackendReplier<MyBackend>) {
+    let run = CalibrationRun {
+        input,
+        samples: Vec::new(),
+        replier,
+    };
+    run.request_next_target();
+    *CALIBRATION_RUN.lock().unwrap() = Some(run);
+}
+
+pub fn cancel_calibration() {
+    *CALIBRATION_RUN.lock().unwrap() = None;
+}
+
+/// Called by the input readers whenever a new contact starts, with its raw position normalized
+/// to the 0.0 - 1.0 range, before any transform is applied.
+pub fn record_calibration_point(input: CalibrationInput, x: f64, y: f64) {
+    let mut current_run = CALIBRATION_RUN.lock().unwrap();
+    let Some(run) = current_run.as_mut().filter(|run| run.input == input) else {
+        return;
+    };
+    run.samples.push((x, y));
+    if run.samples.len() < CALIBRATION_TARGETS.len() {
+        run.request_next_target();
+        return;
+    }
+
+    let run = current_run.take().unwrap();
+    let points = run
+        .samples
+        .iter()
+        .copied()
+        .zip(CALIBRATION_TARGETS)
+        .collect::<Vec<_>>();
+    let result = match Affine::fit(&points) {
+        Some(transform) => {
+            let mut calibrations = CALIBRATIONS.write().unwrap();
+            calibrations.insert((input, current_orientation().degrees()), transform);
+            match save_calibrations(&calibrations) {
+                Ok(()) => "Calibration saved".to_string(),
+                Err(e) => format!("Calibration applied, but couldn't be saved: {e}"),
+            }
+        }
+        None => "Calibration failed - the taps were too close together".to_string(),
+    };
+    println!("{result}");
+    let _ = run.replier.send_message(MSG_CALIBRATION_RESULT, &result);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The reMarkable 2's digitizer transform, with a scale and an offset added.
+    const TRANSFORM: Affine = Affine([0.0, 1.1, 0.05, -0.9, 0.0, 0.95]);
+
+    fn assert_close(actual: Point, expected: Point) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9,
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    #[test]
+    fn fit_recovers_a_known_transform() {
+        let points = CALIBRATION_TARGETS
+            .map(|(x, y)| ((x, y), TRANSFORM.apply(x, y)))
+            .to_vec();
+        let fitted = Affine::fit(&points).unwrap();
+        for (actual, expected) in fitted.0.iter().zip(TRANSFORM.0) {
+            assert!((actual - expected).abs() < 1e-9, "{fitted} != {TRANSFORM}");
+        }
+        // Exactly 3 points are enough.
+        assert!(Affine::fit(&points[..3]).is_some());
+    }
+
+    #[test]
+    fn fit_rejects_collinear_points() {
+        let points = [(0.1, 0.1), (0.5, 0.5), (0.9, 0.9)].map(|e| (e, e));
+        assert_eq!(Affine::fit(&points), None);
+        assert_eq!(Affine::fit(&points[..2]), None);
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let inverse = TRANSFORM.inverse().unwrap();
+        for (x, y) in CALIBRATION_TARGETS {
+            let (tx, ty) = TRANSFORM.apply(x, y);
+            assert_close(inverse.apply(tx, ty), (x, y));
+        }
+        assert_eq!(Affine([1.0, 2.0, 0.0, 2.0, 4.0, 0.0]).inverse(), None);
+    }
+
+    #[test]
+    fn round_trips_through_strings() {
+        assert_eq!(TRANSFORM.to_string().parse(), Ok(TRANSFORM));
+        assert_eq!("1,2,3".parse::<Affine>(), Err(()));
+        assert_eq!("1,2,3,4,5,x".parse::<Affine>(), Err(()));
+    }
+}
@@ -0,0 +1,154 @@
+use std::io::Write;
+use std::time::Instant;
+
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use crate::metrics::record_compression;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Codec {
+    None,
+    Deflate,
+    Zstd,
+    Lz4,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 4] = [Codec::None, Codec::Deflate, Codec::Zstd, Codec::Lz4];
+
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Deflate => 1,
+            Codec::Zstd => 2,
+            Codec::Lz4 => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.id() == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Deflate => "deflate",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.name() == name)
+    }
+
+    pub fn default_level(self) -> i32 {
+        match self {
+            Codec::Deflate => 6,
+            Codec::Zstd => zstd::DEFAULT_COMPRESSION_LEVEL,
+            Codec::None | Codec::Lz4 => 0,
+        }
+    }
+
+    /// Brings `level` into the range the codec accepts. Codecs without levels always get 0.
+    pub fn clamp_level(self, level: i32) -> i32 {
+        match self {
+            Codec::Deflate => level.clamp(0, 9),
+            Codec::Zstd => level.clamp(*zstd::compression_level_range().start(), 19),
+            Codec::None | Codec::Lz4 => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CodecSettings {
+    pub codec: Codec,
+    pub level: i32,
+}
+
+impl CodecSettings {
+    pub fn new(codec: Codec, level: Option<i32>) -> Self {
+        Self {
+            codec,
+            level: codec.clamp_level(level.unwrap_or(codec.default_level())),
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let start = Instant::now();
+        let compressed = match self.codec {
+            Codec::None => data.to_vec(),
+            Codec::Deflate => {
+                let mut encoder = DeflateEncoder::new(
+                    Vec::with_capacity(data.len() / 4),
+                    Compression::new(self.level as u32),
+                );
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Codec::Zstd => zstd::bulk::compress(data, self.level).unwrap(),
+            Codec::Lz4 => lz4_flex::block::compress(data),
+        };
+        record_compression(self.codec, data.len(), compressed.len(), start.elapsed());
+        compressed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::DeflateDecoder;
+
+    use super::*;
+
+    fn decompress(codec: Codec, data: &[u8], size: usize) -> Vec<u8> {
+        match codec {
+            Codec::None => data.to_vec(),
+            Codec::Deflate => {
+                let mut decompressed = Vec::new();
+                DeflateDecoder::new(data)
+                    .read_to_end(&mut decompressed)
+                    .unwrap();
+                decompressed
+            }
+            Codec::Zstd => zstd::bulk::decompress(data, size).unwrap(),
+            Codec::Lz4 => lz4_flex::block::decompress(data, size).unwrap(),
+        }
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        let data = (0..100_000u32)
+            .map(|i| if i % 1000 < 300 { (i % 7) as u8 } else { 0xff })
+            .collect::<Vec<_>>();
+        for codec in Codec::ALL {
+            for level in [None, Some(i32::MIN), Some(i32::MAX)] {
+                let settings = CodecSettings::new(codec, level);
+                let compressed = settings.compress(&data);
+                assert_eq!(
+                    decompress(codec, &compressed, data.len()),
+                    data,
+                    "{codec:?} at level {}",
+                    settings.level
+                );
+                if codec != Codec::None && level.is_none() {
+                    assert!(compressed.len() < data.len(), "{codec:?}");
+                }
+            }
+            assert!(
+                decompress(codec, &CodecSettings::new(codec, None).compress(&[]), 0).is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn ids_and_names_round_trip() {
+        for codec in Codec::ALL {
+            assert_eq!(Codec::from_id(codec.id()), Some(codec));
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+        }
+        assert_eq!(Codec::from_id(4), None);
+    }
+}
@@ -0,0 +1,306 @@
+use std::borrow::Cow;
+
+use lazy_static::lazy_static;
+
+use crate::devices::detect_device;
+
+/// The palette sent to clients using `ColorMode::Palette`: the packet type, the number of colours (1 - 256)
+/// as a u16 BE, then the red, green and blue bytes of each colour, in index order.
+pub const PALETTE_PACKET: u8 = 11;
+
+/// The index sizes clients can ask for with `ColorMode::Palette`.
+pub const INDEX_BITS: [u8; 4] = [1, 2, 4, 8];
+
+/// Used on devices whose profile doesn't list their colours: the 16 gray levels of e-ink panels.
+const DEFAULT_PALETTE: [[u8; 3]; 16] = {
+    let mut palette = [[0u8; 3]; 16];
+    let mut i = 0;
+    while i < 16 {
+        let gray = (i * 17) as u8;
+        palette[i] = [gray, gray, gray];
+        i += 1;
+    }
+    palette
+};
+
+lazy_static! {
+    static ref DEVICE_PALETTE: Palette = load_device_palette();
+    /// The palettes for each of `INDEX_BITS`.
+    static ref PALETTES: Vec<Palette> = INDEX_BITS
+        .into_iter()
+        .map(|bits| DEVICE_PALETTE.fit(bits))
+        .collect();
+}
+
+fn load_device_palette() -> Palette {
+    match detect_device().ok().and_then(|e| e.palette.clone()) {
+        Some(colors) if (1..=256).contains(&colors.len()) => Palette { colors },
+        Some(_) => {
+            eprintln!("A palette has to have between 1 and 256 colours. Using the default.");
+            Palette::default()
+        }
+        None => Palette::default(),
+    }
+}
+
+/// The palette used with `index_bits`-bit indices: the colours the device profile lists for the screen,
+/// unless there are too many of them. Built-in profiles only approximate the colours of colour panels.
+pub fn palette(index_bits: u8) -> &'static Palette {
+    let i = INDEX_BITS.iter().position(|e| *e == index_bits).unwrap();
+    &PALETTES[i]
+}
+
+/// How the pixels of deltas and keyframes are represented for a client.
+///
+/// In modes other than `Color`, delta offsets and lengths count pixels instead of RGBA bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColorMode {
+    /// RGBA8888.
+    Color,
+    /// 8-bit luma.
+    Grayscale,
+    /// Indices into a palette of at most 2^`index_bits` colours, sent in a `PALETTE_PACKET` after
+    /// the session packet. Indices smaller than a byte are packed starting from the most significant bit,
+    /// and every delta starts on a new byte.
+    Palette { index_bits: u8 },
+}
+
+impl ColorMode {
+    pub const ALL: [ColorMode; 3] = [
+        ColorMode::Color,
+        ColorMode::Grayscale,
+        ColorMode::Palette { index_bits: 8 },
+    ];
+
+    pub fn id(self) -> u8 {
+        match self {
+            ColorMode::Color => 0,
+            ColorMode::Grayscale => 1,
+            ColorMode::Palette { .. } => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.id() == id)
+    }
+
+    /// The size of a converted pixel in bytes, before the indices are packed.
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            ColorMode::Color => 4,
+            ColorMode::Grayscale | ColorMode::Palette { .. } => 1,
+        }
+    }
+
+    pub fn palette(self) -> Option<&'static Palette> {
+        match self {
+            ColorMode::Palette { index_bits } => Some(palette(index_bits)),
+            ColorMode::Color | ColorMode::Grayscale => None,
+        }
+    }
+
+    /// Converts RGBA8888 pixels into this mode's representation, with a byte for every palette index.
+    pub fn convert(self, rgba: &[u8]) -> Vec<u8> {
+        match self {
+            ColorMode::Color => rgba.to_vec(),
+            ColorMode::Grayscale => rgba
+                .chunks_exact(4)
+                .map(|pixel| luma([pixel[0], pixel[1], pixel[2]]))
+                .collect(),
+            ColorMode::Palette { index_bits } => palette(index_bits).indices(rgba),
+        }
+    }
+
+    /// The inverse of `convert`, minus whatever the conversion lost.
+    pub fn expand(self, converted: &[u8]) -> Vec<u8> {
+        match self {
+            ColorMode::Color => converted.to_vec(),
+            ColorMode::Grayscale => converted
+                .iter()
+                .flat_map(|gray| [*gray, *gray, *gray, 0xFF])
+                .collect(),
+            ColorMode::Palette { index_bits } => palette(index_bits).colors_of(converted),
+        }
+    }
+
+    /// Packs converted pixels the way they're sent. Only indices smaller than a byte change.
+    pub fn pack(self, converted: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            ColorMode::Palette { index_bits } if index_bits < 8 => {
+                Cow::Owned(pack_indices(converted, index_bits))
+            }
+            _ => Cow::Borrowed(converted),
+        }
+    }
+
+    /// Packs a `width`-pixel wide image row by row, every row starting on a new byte, as PNG does.
+    pub fn pack_rows(self, converted: &[u8], width: u32) -> Cow<'_, [u8]> {
+        match self {
+            ColorMode::Palette { index_bits } if index_bits < 8 => Cow::Owned(
+                converted
+                    .chunks(width as usize)
+                    .flat_map(|row| pack_indices(row, index_bits))
+                    .collect(),
+            ),
+            _ => Cow::Borrowed(converted),
+        }
+    }
+}
+
+/// Packs `bits`-bit indices into bytes, the first index in the most significant bits.
+fn pack_indices(indices: &[u8], bits: u8) -> Vec<u8> {
+    let per_byte = (8 / bits) as usize;
+    indices
+        .chunks(per_byte)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(0u8, |byte, (i, index)| {
+                byte | (index << (8 - bits as usize * (i + 1)))
+            })
+        })
+        .collect()
+}
+
+fn luma([r, g, b]: [u8; 3]) -> u8 {
+    ((299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b)) / 1000) as u8
+}
+
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self {
+            colors: DEFAULT_PALETTE.to_vec(),
+        }
+    }
+}
+
+impl Palette {
+    /// Returns a palette of at most 2^`bits` colours. Palettes which are too large are replaced by
+    /// evenly spaced grays, as there's no room left for the colours anyway.
+    fn fit(&self, bits: u8) -> Palette {
+        let size = 1usize << bits;
+        if self.colors.len() <= size {
+            return Palette {
+                colors: self.colors.clone(),
+            };
+        }
+        Palette {
+            colors: (0..size)
+                .map(|i| {
+                    let gray = (i * 255 / (size - 1)) as u8;
+                    [gray, gray, gray]
+                })
+                .collect(),
+        }
+    }
+
+    /// Maps RGBA8888 pixels onto the indices of their closest colours.
+    fn indices(&self, rgba: &[u8]) -> Vec<u8> {
+        // E-ink frames are mostly long runs of the same colour.
+        let mut previous: Option<([u8; 3], u8)> = None;
+        rgba.chunks_exact(4)
+            .map(|pixel| {
+                let color = [pixel[0], pixel[1], pixel[2]];
+                match previous {
+                    Some((previous_color, index)) if previous_color == color => index,
+                    _ => {
+                        let index = self.nearest(color);
+                        previous = Some((color, index));
+                        index
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn colors_of(&self, indices: &[u8]) -> Vec<u8> {
+        indices
+            .iter()
+            .flat_map(|index| {
+                let [r, g, b] = self.colors[*index as usize];
+                [r, g, b, 0xFF]
+            })
+            .collect()
+    }
+
+    /// Returns the index of the closest colour.
+    pub fn nearest(&self, color: [u8; 3]) -> u8 {
+        let distance = |other: &[u8; 3]| -> u32 {
+            color
+                .iter()
+                .zip(other)
+                .map(|(a, b)| u32::from(a.abs_diff(*b)).pow(2))
+                .sum()
+        };
+        self.colors
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, other)| distance(other))
+            .map_or(0, |(i, _)| i as u8)
+    }
+
+    /// The palette as PNG's PLTE chunk expects it.
+    pub fn rgb_bytes(&self) -> Vec<u8> {
+        self.colors.iter().flatten().copied().collect()
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut packet = vec![PALETTE_PACKET];
+        packet.extend_from_slice(&(self.colors.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&self.rgb_bytes());
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grayscale_uses_luma() {
+        let rgba = [255, 255, 255, 255, 255, 0, 0, 255];
+        assert_eq!(ColorMode::Grayscale.convert(&rgba), [255, 76]);
+    }
+
+    #[test]
+    fn palette_picks_the_nearest_color() {
+        let palette = Palette {
+            colors: vec![[0, 0, 0], [255, 255, 255], [255, 0, 0]],
+        };
+        let rgba = [250, 250, 250, 255, 200, 30, 30, 255, 10, 10, 10, 255];
+        let indices = palette.indices(&rgba);
+        assert_eq!(indices, [1, 2, 0]);
+        assert_eq!(
+            palette.colors_of(&indices),
+            [255, 255, 255, 255, 255, 0, 0, 255, 0, 0, 0, 255]
+        );
+    }
+
+    #[test]
+    fn default_palette_has_16_grays() {
+        let palette = Palette::default();
+        assert_eq!(palette.colors.first(), Some(&[0, 0, 0]));
+        assert_eq!(palette.colors.last(), Some(&[255, 255, 255]));
+        assert_eq!(palette.nearest([120, 120, 120]), 7);
+    }
+
+    #[test]
+    fn packs_indices_from_the_most_significant_bit() {
+        assert_eq!(
+            pack_indices(&[1, 0, 1, 1, 0, 0, 0, 0, 1], 1),
+            [0b1011_0000, 0b1000_0000]
+        );
+        assert_eq!(pack_indices(&[3, 0, 2], 2), [0b1100_1000]);
+        assert_eq!(pack_indices(&[0xA, 0x5, 0xF], 4), [0xA5, 0xF0]);
+    }
+
+    #[test]
+    fn large_palettes_are_replaced_by_grays() {
+        let palette = Palette::default();
+        assert_eq!(palette.fit(4).colors, palette.colors);
+        assert_eq!(palette.fit(1).colors, [[0, 0, 0], [255, 255, 255]]);
+        assert_eq!(palette.fit(2).colors[1], [85, 85, 85]);
+    }
+}
@@ -0,0 +1,159 @@
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+
+/// How the framebuffer is captured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// The whole framebuffer is read every poll.
+    Poll,
+    /// Experimental: only the rows reported as damaged are read, with a slow full poll as a fallback.
+    /// The reports are `framebuffer-spy$damage` signals carrying `x,y,width,height`, relayed by the frontend.
+    /// No released framebuffer-spy version sends them yet, so the report format is only a proposal,
+    /// and until the first report arrives, the framebuffer is polled as usual.
+    Damage,
+}
+
+impl FromStr for CaptureMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poll" => Ok(CaptureMode::Poll),
+            "experimental-damage" => Ok(CaptureMode::Damage),
+            _ => Err(()),
+        }
+    }
+}
+
+lazy_static! {
+    static ref DAMAGE: DamageTracker = DamageTracker::default();
+}
+
+/// Collects the damage reports until the capture loop picks them up.
+#[derive(Default)]
+struct DamageTracker {
+    /// Set once the first report arrives.
+    reported: AtomicBool,
+    /// The rows damaged since the last capture.
+    pending: Mutex<Option<Range<u32>>>,
+    notify: Notify,
+}
+
+impl DamageTracker {
+    fn report(&self, rows: Range<u32>) {
+        self.reported.store(true, Ordering::Relaxed);
+        let mut pending = self.pending.lock().unwrap();
+        *pending = Some(match pending.take() {
+            Some(previous) => previous.start.min(rows.start)..previous.end.max(rows.end),
+            None => rows,
+        });
+        self.notify.notify_one();
+    }
+
+    fn clear(&self) {
+        self.pending.lock().unwrap().take();
+    }
+
+    async fn next(&self, fallback: Duration) -> Option<Range<u32>> {
+        if !self.reported.load(Ordering::Relaxed) {
+            return None;
+        }
+        loop {
+            // Reports arriving after the check leave a permit behind, so they aren't missed.
+            let notified = self.notify.notified();
+            if let Some(rows) = self.pending.lock().unwrap().take() {
+                return Some(rows);
+            }
+            if timeout(fallback, notified).await.is_err() {
+                return None;
+            }
+        }
+    }
+}
+
+/// Parses a damage report relayed by the frontend: `x,y,width,height` in framebuffer pixels.
+fn parse_damage(contents: &str) -> Option<Range<u32>> {
+    let values = contents
+        .split(',')
+        .map(|e| e.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?;
+    match values[..] {
+        [_, y, _, height] => Some(y..y.saturating_add(height)),
+        _ => None,
+    }
+}
+
+/// Records a damage report. The rows are merged with the ones which weren't captured yet.
+pub fn report_damage(contents: &str) {
+    match parse_damage(contents) {
+        Some(rows) => DAMAGE.report(rows),
+        None => eprintln!("Invalid damage report: {contents}"),
+    }
+}
+
+/// Forgets the pending damage, once the whole framebuffer was read anyway.
+pub fn clear_damage() {
+    DAMAGE.clear();
+}
+
+/// Waits up to `fallback` for damaged rows. Returns `None` if the whole framebuffer should be read instead:
+/// when nothing was reported in time, or nothing was ever reported at all.
+pub async fn next_damage(fallback: Duration) -> Option<Range<u32>> {
+    DAMAGE.next(fallback).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FALLBACK: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn parses_damage_reports() {
+        assert_eq!(parse_damage("10, 20, 30, 40"), Some(20..60));
+        assert_eq!(parse_damage("10,20,30"), None);
+        assert_eq!(parse_damage("a,b,c,d"), None);
+    }
+
+    #[test]
+    fn damage_capture_has_to_be_asked_for_as_experimental() {
+        assert_eq!("poll".parse(), Ok(CaptureMode::Poll));
+        assert_eq!("experimental-damage".parse(), Ok(CaptureMode::Damage));
+        assert_eq!("damage".parse::<CaptureMode>(), Err(()));
+    }
+
+    #[tokio::test]
+    async fn polls_until_the_first_report() {
+        let tracker = DamageTracker::default();
+        assert_eq!(tracker.next(FALLBACK).await, None);
+    }
+
+    #[tokio::test]
+    async fn merges_the_pending_reports() {
+        let tracker = DamageTracker::default();
+        tracker.report(20..30);
+        tracker.report(5..10);
+        assert_eq!(tracker.next(FALLBACK).await, Some(5..30));
+        // Once taken, the rows aren't reported again.
+        assert_eq!(tracker.next(FALLBACK).await, None);
+    }
+
+    #[tokio::test]
+    async fn waits_for_reports_until_the_fallback() {
+        let tracker = std::sync::Arc::new(DamageTracker::default());
+        tracker.report(0..1);
+        tracker.clear();
+        let reporter = tracker.clone();
+        tokio::spawn(async move { reporter.report(3..4) });
+        assert_eq!(tracker.next(Duration::from_secs(5)).await, Some(3..4));
+        assert_eq!(tracker.next(FALLBACK).await, None);
+    }
+}
@@ -1,170 +1,420 @@
-use crate::framebuffer_spy::FramebufferSpyConfig;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Result};
+use evdev::{AbsInfo, AbsoluteAxisCode, KeyCode};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+use crate::calibration::Affine;
+use crate::framebuffer_spy::{invalid_field, FramebufferSpyConfig};
+use crate::orientation::current_orientation;
+use crate::pixel_format::PixelFormat;
+use crate::profiles::detect_profile;
+
+/// Raw values reported by the digitizer.
+#[derive(Default, Clone, Copy)]
+pub struct DigitizerData {
+    pub x: i32,
+    pub y: i32,
+    pub distance: i32,
+    pub pressure: i32,
+    pub tilt_x: i32,
+    pub tilt_y: i32,
+}
+
+/// Digitizer data mapped onto the screen.
+pub struct PenData {
+    /// Horizontal position on the screen, from 0.0 to 1.0.
+    pub x: f64,
+    /// Vertical position on the screen, from 0.0 to 1.0.
+    pub y: f64,
+    /// From 0.0 to 1.0.
+    pub pressure: f64,
+    /// Tilt along the screen's horizontal axis, from -1.0 to 1.0.
+    pub tilt_x: f64,
+    /// Tilt along the screen's vertical axis, from -1.0 to 1.0.
+    pub tilt_y: f64,
+    /// Hover distance, from 0.0 to 1.0.
+    pub distance: f64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct AxisRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl AxisRange {
+    /// Maps the value onto 0.0 - 1.0.
+    pub fn normalize(&self, value: i32) -> f64 {
+        if self.max <= self.min {
+            return 0.0;
+        }
+        (f64::from(value) - self.min) / (self.max - self.min)
+    }
+
+    /// The inverse of `normalize`.
+    pub fn denormalize(&self, value: f64) -> i32 {
+        (self.min + value * (self.max - self.min)).round() as i32
+    }
+
+    /// Maps the value onto -1.0 - 1.0, keeping 0 in place.
+    pub fn normalize_symmetric(&self, value: i32) -> f64 {
+        let extent = f64::max(self.min.abs(), self.max.abs());
+        if extent == 0.0 {
+            return 0.0;
+        }
+        f64::from(value) / extent
+    }
+}
+
+impl From<AbsInfo> for AxisRange {
+    fn from(value: AbsInfo) -> Self {
+        Self {
+            min: f64::from(value.minimum()),
+            max: f64::from(value.maximum()),
+        }
+    }
+}
+
+/// What a profile knows about the digitizer. The path replaces the detection, while the axis ranges only
+/// stand in for the ones the device doesn't report.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DigitizerProfile {
+    pub path: Option<String>,
+    pub x: Option<AxisRange>,
+    pub y: Option<AxisRange>,
+    pub pressure: Option<AxisRange>,
+    pub tilt_x: Option<AxisRange>,
+    pub tilt_y: Option<AxisRange>,
+    pub distance: Option<AxisRange>,
+}
+
+/// The pen digitizer, as found at runtime.
+#[derive(Debug)]
+pub struct Digitizer {
+    pub path: PathBuf,
+    pub x: AxisRange,
+    pub y: AxisRange,
+    pub pressure: AxisRange,
+    pub tilt_x: AxisRange,
+    pub tilt_y: AxisRange,
+    pub distance: AxisRange,
+}
+
+impl Digitizer {
+    /// The position in the digitizer's own coordinates, normalized to 0.0 - 1.0.
+    pub fn normalize_position(&self, data: &DigitizerData) -> (f64, f64) {
+        (self.x.normalize(data.x), self.y.normalize(data.y))
+    }
+
+    pub fn translate(&self, transform: &Affine, data: &DigitizerData) -> PenData {
+        let (x, y) = self.normalize_position(data);
+        let (x, y) = transform.apply(x, y);
+        let (tilt_x, tilt_y) = transform.apply_linear(
+            self.tilt_x.normalize_symmetric(data.tilt_x),
+            self.tilt_y.normalize_symmetric(data.tilt_y),
+        );
+        PenData {
+            x,
+            y,
+            pressure: self.pressure.normalize(data.pressure),
+            tilt_x,
+            tilt_y,
+            distance: self.distance.normalize(data.distance),
+        }
+    }
+}
+
+/// The multitouch touchscreen, as found at runtime.
+#[derive(Debug)]
+pub struct Touchscreen {
+    pub path: PathBuf,
+    pub x: AxisRange,
+    pub y: AxisRange,
+    /// How many contacts the touchscreen can track at once.
+    pub slots: usize,
+}
+
+impl Touchscreen {
+    /// The position in the touchscreen's own coordinates, normalized to 0.0 - 1.0.
+    pub fn normalize_position(&self, x: i32, y: i32) -> (f64, f64) {
+        (self.x.normalize(x), self.y.normalize(y))
+    }
+}
 
 pub struct Device {
-    pub digitizer_path: &'static str,
-    pub digitizer_data_translator: fn(&Device, i32, i32, i32) -> (i32, i32, i32),
-    pub max_digitizer_width: f64,
-    pub max_digitizer_height: f64,
-    pub override_framebuffer_config: Option<&'static FramebufferConfig>,
+    pub name: String,
+    /// Maps the normalized digitizer position onto the screen, unless a calibration overrides it.
+    pub digitizer_transform: Affine,
+    pub digitizer_profile: DigitizerProfile,
+    /// Maps the normalized touchscreen position onto the screen, unless a calibration overrides it.
+    pub touch_transform: Affine,
+    pub touchscreen_path: Option<String>,
+    /// Where to read the screen from, if not from xochitl's memory as located by framebuffer-spy.
+    pub framebuffer_file: Option<FramebufferFile>,
+    /// The colours the screen can show, as RGB. `None` for the 16 grays of monochrome panels.
+    pub palette: Option<Vec<[u8; 3]>>,
+}
+
+fn is_stylus(device: &evdev::Device) -> bool {
+    device
+        .supported_keys()
+        .is_some_and(|keys| keys.contains(KeyCode::BTN_TOOL_PEN))
+        && device.supported_absolute_axes().is_some_and(|axes| {
+            axes.contains(AbsoluteAxisCode::ABS_X) && axes.contains(AbsoluteAxisCode::ABS_Y)
+        })
+}
+
+fn is_touchscreen(device: &evdev::Device) -> bool {
+    device.supported_absolute_axes().is_some_and(|axes| {
+        axes.contains(AbsoluteAxisCode::ABS_MT_SLOT)
+            && axes.contains(AbsoluteAxisCode::ABS_MT_POSITION_X)
+            && axes.contains(AbsoluteAxisCode::ABS_MT_POSITION_Y)
+    }) && !is_stylus(device)
+}
+
+impl Device {
+    /// Finds the stylus among the input devices and reads its axis ranges.
+    pub fn find_digitizer(&self) -> Result<Digitizer> {
+        let profile = &self.digitizer_profile;
+        let (path, device) = match &profile.path {
+            Some(path) => (PathBuf::from(path), evdev::Device::open(path)?),
+            None => match evdev::enumerate().find(|(_, device)| is_stylus(device)) {
+                Some(found) => found,
+                None => bail!("No input device with a pen and absolute X and Y axes found"),
+            },
+        };
+        let axes = device.get_absinfo()?.collect::<HashMap<_, _>>();
+        let axis = |code: AbsoluteAxisCode, fallback: Option<AxisRange>| {
+            axes.get(&code).copied().map(AxisRange::from).or(fallback)
+        };
+        // Optional axes stay at 0 if they're not reported, so their range doesn't matter.
+        let unreported = AxisRange { min: 0.0, max: 1.0 };
+        Ok(Digitizer {
+            x: axis(AbsoluteAxisCode::ABS_X, profile.x)
+                .ok_or_else(|| anyhow!("{path:?} has no X axis"))?,
+            y: axis(AbsoluteAxisCode::ABS_Y, profile.y)
+                .ok_or_else(|| anyhow!("{path:?} has no Y axis"))?,
+            pressure: axis(AbsoluteAxisCode::ABS_PRESSURE, profile.pressure).unwrap_or(unreported),
+            tilt_x: axis(AbsoluteAxisCode::ABS_TILT_X, profile.tilt_x).unwrap_or(unreported),
+            tilt_y: axis(AbsoluteAxisCode::ABS_TILT_Y, profile.tilt_y).unwrap_or(unreported),
+            distance: axis(AbsoluteAxisCode::ABS_DISTANCE, profile.distance).unwrap_or(unreported),
+            path,
+        })
+    }
+
+    /// Finds the multitouch touchscreen among the input devices and reads its axis ranges.
+    pub fn find_touchscreen(&self) -> Result<Touchscreen> {
+        let (path, device) = match &self.touchscreen_path {
+            Some(path) => (PathBuf::from(path), evdev::Device::open(path)?),
+            None => match evdev::enumerate().find(|(_, device)| is_touchscreen(device)) {
+                Some(found) => found,
+                None => bail!("No multitouch input device found"),
+            },
+        };
+        let axes = device.get_absinfo()?.collect::<HashMap<_, _>>();
+        let axis = |code: AbsoluteAxisCode| {
+            axes.get(&code)
+                .copied()
+                .ok_or_else(|| anyhow!("{path:?} has no {code:?} axis"))
+        };
+        Ok(Touchscreen {
+            x: axis(AbsoluteAxisCode::ABS_MT_POSITION_X)?.into(),
+            y: axis(AbsoluteAxisCode::ABS_MT_POSITION_Y)?.into(),
+            slots: (axis(AbsoluteAxisCode::ABS_MT_SLOT)?.maximum().max(0) + 1) as usize,
+            path,
+        })
+    }
+}
+
+/// A framebuffer read directly from a file. Whatever is left out is asked from the framebuffer device.
+pub struct FramebufferFile {
+    pub path: String,
+    pub address: Option<usize>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub stride: Option<usize>,
+    pub pixel_format: Option<PixelFormat>,
 }
 
 pub struct FramebufferConfig {
-    pub framebuffer_file: Option<&'static str>,
     pub address: usize,
+    /// The visible width, in pixels.
     pub width: u32,
     pub height: u32,
+    /// The first visible row of the framebuffer. Damage reports count rows from the top of the framebuffer.
+    pub y_offset: u32,
+    /// The length of a row in memory, in bytes. Can be larger than the visible width.
+    pub stride: usize,
     pub fb_size: usize,
-    pub image_data_translator: fn(&FramebufferConfig, &[u8], &mut [u8]),
+    pub pixel_format: PixelFormat,
 }
 
-impl From<FramebufferSpyConfig> for FramebufferConfig {
-    fn from(value: FramebufferSpyConfig) -> Self {
-        let (pixel_size, image_data_translator): (u32, fn(&FramebufferConfig, &[u8], &mut [u8])) =
-            match value.r#type {
-                2 => (4, rgba_image_data_translator),
-                1 => (2, rgb565_image_data_translator),
-                _ => panic!(),
-            };
-        let fb_size = (value.bpl * value.height) as usize;
-        Self {
-            framebuffer_file: None,
-            address: value.address,
-            fb_size,
-            height: value.height,
-            image_data_translator,
-            width: value.bpl / pixel_size,
+impl FramebufferConfig {
+    /// Converts `rows` of the raw framebuffer into RGBA8888, without the row padding.
+    pub fn translate_rows(&self, in_data: &[u8], out_data: &mut [u8], rows: Range<u32>) {
+        let rows = rows.start.min(self.height) as usize..rows.end.min(self.height) as usize;
+        let out_stride = self.width as usize * 4;
+        self.pixel_format.translate_rows(
+            &in_data[(rows.start * self.stride).min(in_data.len())..],
+            self.stride,
+            self.width,
+            &mut out_data[rows.start * out_stride..rows.end * out_stride],
+        );
+    }
+
+    /// Converts damaged framebuffer rows into visible rows, dropping the ones outside the visible part.
+    pub fn visible_rows(&self, rows: Range<u32>) -> Range<u32> {
+        let visible = |row: u32| row.saturating_sub(self.y_offset).min(self.height);
+        visible(rows.start)..visible(rows.end)
+    }
+
+    /// The size of the frames sent to the clients, after they're rotated.
+    pub fn streamed_size(&self) -> (u32, u32) {
+        current_orientation().rotated_size(self.width, self.height)
+    }
+}
+
+impl TryFrom<FramebufferSpyConfig> for FramebufferConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: FramebufferSpyConfig) -> Result<Self> {
+        let pixel_format = value
+            .pixel_format
+            .or_else(|| PixelFormat::from_spy_type(value.r#type))
+            .ok_or_else(|| {
+                anyhow!(
+                    "framebuffer-spy reported an unsupported pixel format (type {})",
+                    value.r#type
+                )
+            })?;
+        let bits_per_pixel = u64::from(pixel_format.bits_per_pixel());
+        let stride = value.bpl as usize;
+        let row_pixels = u64::from(value.bpl) * 8 / bits_per_pixel;
+        if row_pixels == 0 {
+            return Err(invalid_field(
+                "stride",
+                &value.bpl.to_string(),
+                "a row doesn't hold a single pixel",
+            )
+            .into());
+        }
+        if value.height == 0 {
+            return Err(invalid_field("height", "0", "the buffer has no rows").into());
         }
+        let offset = || format!("{}x{}", value.x_offset, value.y_offset);
+        if u64::from(value.x_offset) >= row_pixels || value.y_offset >= value.height {
+            return Err(invalid_field(
+                "offset",
+                &offset(),
+                format!(
+                    "the buffer only has {row_pixels} pixels per row and {} rows",
+                    value.height
+                ),
+            )
+            .into());
+        }
+        if u64::from(value.x_offset) * bits_per_pixel % 8 != 0 {
+            return Err(invalid_field("offset", &offset(), "it doesn't start on a byte").into());
+        }
+        // The row can't hold more pixels than fit into a line. Older framebuffer-spy versions don't report a width.
+        let max_width = (row_pixels - u64::from(value.x_offset)) as u32;
+        let width = match value.width {
+            0 => max_width,
+            width => width.min(max_width),
+        };
+        // The visible part ends with the buffer.
+        let height = value.height - value.y_offset;
+        let x_offset_bytes = (u64::from(value.x_offset) * bits_per_pixel / 8) as usize;
+        let visible_offset = value.y_offset as usize * stride + x_offset_bytes;
+        // Starting mid-row, the last row ends early so as not to read past the buffer.
+        let fb_size = stride * height as usize - x_offset_bytes;
+        let address = value.address.checked_add(visible_offset).ok_or_else(|| {
+            invalid_field(
+                "offset",
+                &offset(),
+                "it's past the end of the address space",
+            )
+        })?;
+        Ok(Self {
+            address,
+            fb_size,
+            height,
+            y_offset: value.y_offset,
+            stride,
+            pixel_format,
+            width,
+        })
     }
 }
 
-pub const RM1_FRAMEBUFFER_CONFIG: FramebufferConfig = FramebufferConfig {
-    framebuffer_file: Some("/dev/fb0"),
-    address: 0,
-    fb_size: 1872 * 1408 * 2,
-    height: 1872,
-    width: 1408,
-    image_data_translator: rgb565_image_data_translator,
-};
-
-pub enum ReMarkableDevice {
-    RM1,
-    RM2,
-    RMPP,
-    RMPPMove,
-    RMPPure
-}
-
-fn rgba_image_data_translator(config: &FramebufferConfig, in_data: &[u8], out_data: &mut [u8]) {
-    for i in 0..(config.width * config.height) as usize {
-        let a = in_data[4 * i + 2];
-        let b = in_data[4 * i + 1];
-        let c = in_data[4 * i];
-        let d = in_data[4 * i + 3];
-        out_data[4 * i] = a;
-        out_data[4 * i + 1] = b;
-        out_data[4 * i + 2] = c;
-        out_data[4 * i + 3] = d;
-    }
-}
-
-fn rmpp_digitizer_translator(device: &Device, x: i32, y: i32, d: i32) -> (i32, i32, i32) {
-    (
-        ((f64::from(x) / device.max_digitizer_width) * 100.0) as i32,
-        ((f64::from(y) / device.max_digitizer_height) * 100.0) as i32,
-        i32::min(d, 1),
-    )
-}
-
-fn rm2_digitizer_translator(device: &Device, x: i32, y: i32, d: i32) -> (i32, i32, i32) {
-    (
-        ((f64::from(y) / device.max_digitizer_height) * 100.0) as i32,
-        (((device.max_digitizer_width - f64::from(x)) / device.max_digitizer_width) * 100.0) as i32,
-        i32::min(d, 1),
-    )
-}
-
-fn rm1_digitizer_translator(device: &Device, x: i32, y: i32, _d: i32) -> (i32, i32, i32) {
-    (
-        ((f64::from(y) / device.max_digitizer_width) * 100.0) as i32,
-        (((device.max_digitizer_height - f64::from(x)) / device.max_digitizer_height) * 100.0)
-            as i32,
-        1,
-    )
-}
-
-fn rgb565_image_data_translator(config: &FramebufferConfig, in_data: &[u8], out_data: &mut [u8]) {
-    for i in 0..(config.width * config.height) as usize {
-        let a = in_data[2 * i + 1] as u16;
-        let b = in_data[2 * i] as u16;
-        let total = (a << 8) | b;
-        let r5 = (total >> 11) & 0x1F;
-        let g6 = (total >> 5) & 0x3F;
-        let b5 = total & 0x1F;
-        let r8 = ((r5 * 255) / 31) as u8;
-        let g8 = ((g6 * 255) / 63) as u8;
-        let b8 = ((b5 * 255) / 31) as u8;
-        out_data[4 * i] = r8;
-        out_data[4 * i + 1] = g8;
-        out_data[4 * i + 2] = b8;
-        out_data[4 * i + 3] = 0xFF;
-    }
-}
-
-pub fn get_device_info(r#type: ReMarkableDevice) -> Device {
-    match r#type {
-        ReMarkableDevice::RMPPure => Device {
-            digitizer_path: "/dev/input/event2",
-            digitizer_data_translator: rmpp_digitizer_translator,
-            max_digitizer_width: 9620.0,
-            max_digitizer_height: 13000.0,
-            override_framebuffer_config: None,
-        },
-        ReMarkableDevice::RMPPMove => Device {
-            digitizer_path: "/dev/input/event2",
-            digitizer_data_translator: rmpp_digitizer_translator,
-            max_digitizer_width: 6760.0,
-            max_digitizer_height: 11960.0,
-            override_framebuffer_config: None,
-        },
-        ReMarkableDevice::RMPP => Device {
-            digitizer_path: "/dev/input/event2",
-            digitizer_data_translator: rmpp_digitizer_translator,
-            max_digitizer_width: 11180.0,
-            max_digitizer_height: 15340.0,
-            override_framebuffer_config: None,
-        },
-        ReMarkableDevice::RM2 => Device {
-            digitizer_path: "/dev/input/event1",
-
-            digitizer_data_translator: rm2_digitizer_translator,
-            max_digitizer_width: 20967.0,
-            max_digitizer_height: 15725.0,
-            override_framebuffer_config: None,
-        },
-        ReMarkableDevice::RM1 => Device {
-            digitizer_path: "/dev/input/event0",
-
-            digitizer_data_translator: rm1_digitizer_translator,
-            max_digitizer_width: 15725.0,
-            max_digitizer_height: 20967.0,
-            override_framebuffer_config: Some(&RM1_FRAMEBUFFER_CONFIG),
-        },
-    }
-}
-
-pub fn detect_device() -> Option<ReMarkableDevice> {
-    let device_type_file = std::fs::read_to_string("/sys/devices/soc0/machine")
+lazy_static! {
+    static ref DETECTED_DEVICE: Result<Device, String> =
+        detect_profile().map_err(|e| e.to_string());
+}
+
+/// Returns the profile of the device we're running on. Detected once, on the first call.
+pub fn detect_device() -> Result<&'static Device> {
+    DETECTED_DEVICE.as_ref().map_err(|e| anyhow!("{e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spy_config(x_offset: u32, y_offset: u32) -> FramebufferSpyConfig {
+        FramebufferSpyConfig::parse(&format!(
+            "v2,address=0x1000,height=10,format=y8,stride=16,offset={x_offset}x{y_offset}"
+        ))
         .unwrap()
-        .to_lowercase();
-    if device_type_file.contains("tatsu") {
-        Some(ReMarkableDevice::RMPPure)
-    } else if device_type_file.contains("chiappa") {
-        Some(ReMarkableDevice::RMPPMove)
-    } else if device_type_file.contains("ferrari") {
-        Some(ReMarkableDevice::RMPP)
-    } else if device_type_file.contains("2.0") {
-        Some(ReMarkableDevice::RM2)
-    } else {
-        Some(ReMarkableDevice::RM1)
+    }
+
+    #[test]
+    fn offsets_shrink_the_visible_part() {
+        let config = FramebufferConfig::try_from(spy_config(4, 2)).unwrap();
+        assert_eq!(config.address, 0x1000 + 2 * 16 + 4);
+        assert_eq!((config.width, config.height), (12, 8));
+        // The last visible byte is the last byte of the buffer.
+        assert_eq!(config.address + config.fb_size, 0x1000 + 16 * 10);
+    }
+
+    #[test]
+    fn damaged_rows_skip_the_offset() {
+        let config = FramebufferConfig::try_from(spy_config(0, 2)).unwrap();
+        assert_eq!(config.visible_rows(0..2), 0..0);
+        assert_eq!(config.visible_rows(1..5), 0..3);
+        assert_eq!(config.visible_rows(4..20), 2..8);
+        assert_eq!(config.visible_rows(12..20), 8..8);
+    }
+
+    #[test]
+    fn rejects_offsets_outside_the_buffer() {
+        for (x_offset, y_offset) in [(16, 0), (0, 10), (u32::MAX, 0), (0, u32::MAX)] {
+            let Err(error) = FramebufferConfig::try_from(spy_config(x_offset, y_offset)) else {
+                panic!("accepted the offset {x_offset}x{y_offset}");
+            };
+            assert!(error.to_string().starts_with("invalid offset"), "{error}");
+        }
+    }
+
+    #[test]
+    fn rejects_empty_buffers() {
+        for (description, field) in [
+            ("v2,address=0x1000,height=10,format=y8,stride=0", "stride"),
+            ("v2,address=0x1000,height=0,format=y8,stride=16", "height"),
+        ] {
+            let config = FramebufferSpyConfig::parse(description).unwrap();
+            let Err(error) = FramebufferConfig::try_from(config) else {
+                panic!("accepted {description}");
+            };
+            assert!(
+                error.to_string().starts_with(&format!("invalid {field}")),
+                "{error}"
+            );
+        }
     }
 }
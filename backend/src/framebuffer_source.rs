@@ -0,0 +1,465 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::devices::{FramebufferConfig, FramebufferFile};
+use crate::framebuffer_spy::FramebufferSpyConfig;
+use crate::orientation::Orientation;
+use crate::pixel_format::PixelFormat;
+
+const FBIOGET_VSCREENINFO: u32 = 0x4600;
+const FBIOGET_FSCREENINFO: u32 = 0x4602;
+
+/// `struct fb_bitfield` from linux/fb.h.
+#[repr(C)]
+#[derive(Default)]
+struct FbBitfield {
+    offset: u32,
+    length: u32,
+    msb_right: u32,
+}
+
+/// `struct fb_var_screeninfo` from linux/fb.h.
+#[repr(C)]
+#[derive(Default)]
+struct FbVarScreeninfo {
+    xres: u32,
+    yres: u32,
+    xres_virtual: u32,
+    yres_virtual: u32,
+    xoffset: u32,
+    yoffset: u32,
+    bits_per_pixel: u32,
+    grayscale: u32,
+    red: FbBitfield,
+    green: FbBitfield,
+    blue: FbBitfield,
+    transp: FbBitfield,
+    nonstd: u32,
+    activate: u32,
+    height: u32,
+    width: u32,
+    accel_flags: u32,
+    pixclock: u32,
+    left_margin: u32,
+    right_margin: u32,
+    upper_margin: u32,
+    lower_margin: u32,
+    hsync_len: u32,
+    vsync_len: u32,
+    sync: u32,
+    vmode: u32,
+    rotate: u32,
+    colorspace: u32,
+    reserved: [u32; 4],
+}
+
+/// `struct fb_fix_screeninfo` from linux/fb.h.
+#[repr(C)]
+#[derive(Default)]
+struct FbFixScreeninfo {
+    id: [u8; 16],
+    smem_start: libc::c_ulong,
+    smem_len: u32,
+    r#type: u32,
+    type_aux: u32,
+    visual: u32,
+    xpanstep: u16,
+    ypanstep: u16,
+    ywrapstep: u16,
+    line_length: u32,
+    mmio_start: libc::c_ulong,
+    mmio_len: u32,
+    accel: u32,
+    capabilities: u16,
+    reserved: [u16; 2],
+}
+
+/// The geometry a framebuffer device reports about itself.
+struct ScreenInfo {
+    width: u32,
+    height: u32,
+    stride: usize,
+    /// Where the visible part starts, if the device is panned.
+    offset: usize,
+    pixel_format: Option<PixelFormat>,
+}
+
+impl ScreenInfo {
+    /// Returns `None` if the file isn't a framebuffer device.
+    fn query(file: &File) -> Option<Self> {
+        let mut var = FbVarScreeninfo::default();
+        let mut fix = FbFixScreeninfo::default();
+        unsafe {
+            if libc::ioctl(file.as_raw_fd(), FBIOGET_VSCREENINFO as _, &mut var) != 0
+                || libc::ioctl(file.as_raw_fd(), FBIOGET_FSCREENINFO as _, &mut fix) != 0
+            {
+                return None;
+            }
+        }
+        let pixel_format = match (var.bits_per_pixel, var.red.offset, var.transp.length) {
+            (4, _, _) => Some(PixelFormat::Y4),
+            (8, _, _) => Some(PixelFormat::Y8),
+            (16, _, _) => Some(PixelFormat::Rgb565),
+            (32, 0, _) => Some(PixelFormat::Rgba8888),
+            (32, 16, 0) => Some(PixelFormat::Xrgb8888),
+            (32, 16, _) => Some(PixelFormat::Bgra8888),
+            _ => None,
+        };
+        Some(Self {
+            width: var.xres,
+            height: var.yres,
+            stride: fix.line_length as usize,
+            offset: var.yoffset as usize * fix.line_length as usize
+                + (var.xoffset * var.bits_per_pixel / 8) as usize,
+            pixel_format,
+        })
+    }
+}
+
+/// Where the frames are captured from.
+pub enum FramebufferSource {
+    /// Read with lseek and read, like xochitl's memory, which can't be mapped.
+    Memory(File),
+    /// A framebuffer device or a plain file, mapped into our memory.
+    Mapped(MappedFile),
+    /// A file another process exports the framebuffer into. It's read with pread instead of being mapped,
+    /// as the exporter may truncate or replace it at any time.
+    SharedMemory(SharedMemory),
+}
+
+impl FramebufferSource {
+    /// Copies `rows` of the framebuffer into the same place in `data`, which is `config.fb_size` bytes long.
+    pub fn read_rows(
+        &self,
+        config: &FramebufferConfig,
+        data: &mut [u8],
+        rows: Range<u32>,
+    ) -> Result<()> {
+        let start = rows.start as usize * config.stride;
+        let end = (rows.end as usize * config.stride).min(config.fb_size);
+        if start >= end {
+            return Ok(());
+        }
+        match self {
+            FramebufferSource::Memory(file) => {
+                if unsafe {
+                    libc::lseek(
+                        file.as_raw_fd(),
+                        (config.address + start) as libc::off_t,
+                        libc::SEEK_SET,
+                    )
+                } == -1
+                {
+                    bail!("Failed to read memory!");
+                }
+                let read_bytes = unsafe {
+                    libc::read(
+                        file.as_raw_fd(),
+                        data[start..].as_mut_ptr() as *mut libc::c_void,
+                        end - start,
+                    )
+                };
+                if read_bytes != (end - start) as isize {
+                    bail!("Failed to read memory!");
+                }
+            }
+            FramebufferSource::Mapped(map) => {
+                data[start..end]
+                    .copy_from_slice(&map.as_slice()[config.address + start..config.address + end]);
+            }
+            FramebufferSource::SharedMemory(shared) => {
+                shared.read(&mut data[start..end], config.address + start)?
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct SharedMemory {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// The config line the file started with when it was opened, including the line break.
+    header: Vec<u8>,
+}
+
+impl SharedMemory {
+    /// Fills `data` from `offset` in the file. Fails instead of reading a layout other than the one
+    /// the stream was set up with, or past the end of the file.
+    fn read(&self, data: &mut [u8], offset: usize) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        // An exporter which restarted may have replaced the file.
+        let current = std::fs::metadata(&self.path)
+            .with_context(|| format!("{} is gone", self.path.display()))?;
+        if current.ino() != file.metadata()?.ino() {
+            *file = File::open(&self.path)?;
+        }
+        let mut header = vec![0u8; self.header.len()];
+        file.read_exact_at(&mut header, 0)
+            .with_context(|| format!("{} has no framebuffer config", self.path.display()))?;
+        if header != self.header {
+            bail!(
+                "{} changed its framebuffer config. Restart the stream to use the new one",
+                self.path.display()
+            );
+        }
+        file.read_exact_at(data, offset as u64)
+            .with_context(|| format!("{} is too short for the framebuffer", self.path.display()))
+    }
+}
+
+/// A read-only, shared mapping of a whole file.
+pub struct MappedFile {
+    pointer: *const u8,
+    length: usize,
+}
+
+// The mapping is never written through, and stays valid until it's dropped.
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    pub fn new(file: &File, length: usize) -> Result<Self> {
+        if length == 0 {
+            bail!("Can't map an empty file");
+        }
+        let pointer = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                length,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if pointer == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error()).context("Failed to map the framebuffer");
+        }
+        Ok(Self {
+            pointer: pointer as *const u8,
+            length,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.pointer, self.length) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.pointer as *mut libc::c_void, self.length);
+        }
+    }
+}
+
+/// Maps the framebuffer file. The geometry the profile leaves out is asked from the framebuffer device.
+pub fn open_framebuffer_file(
+    framebuffer: &FramebufferFile,
+) -> Result<(FramebufferSource, FramebufferConfig)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(&framebuffer.path)
+        .with_context(|| format!("Failed to open {}", framebuffer.path))?;
+    let info = ScreenInfo::query(&file);
+    let missing = |field: &str| {
+        anyhow!(
+            "{} isn't a framebuffer device, so its {field} has to be set in the device profile",
+            framebuffer.path
+        )
+    };
+    let width = framebuffer
+        .width
+        .or(info.as_ref().map(|e| e.width))
+        .ok_or_else(|| missing("width"))?;
+    let height = framebuffer
+        .height
+        .or(info.as_ref().map(|e| e.height))
+        .ok_or_else(|| missing("height"))?;
+    let pixel_format = framebuffer
+        .pixel_format
+        .or(info.as_ref().and_then(|e| e.pixel_format))
+        .ok_or_else(|| missing("pixel_format"))?;
+    let stride = framebuffer
+        .stride
+        .or(info.as_ref().map(|e| e.stride))
+        .unwrap_or_else(|| (width * pixel_format.bits_per_pixel()).div_ceil(8) as usize);
+    let address = framebuffer
+        .address
+        .or(info.as_ref().map(|e| e.offset))
+        .unwrap_or(0);
+    let config = FramebufferConfig {
+        address,
+        width,
+        height,
+        y_offset: 0,
+        stride,
+        fb_size: stride * height as usize,
+        pixel_format,
+    };
+
+    // Framebuffer devices report their size as 0, so the whole screen memory is mapped instead.
+    let length = match file.metadata()?.len() as usize {
+        0 => address + config.fb_size,
+        length => length,
+    };
+    if address + config.fb_size > length {
+        bail!(
+            "{} is too small for a {width}x{height} {pixel_format} framebuffer",
+            framebuffer.path
+        );
+    }
+    let map = MappedFile::new(&file, length)?;
+    Ok((FramebufferSource::Mapped(map), config))
+}
+
+/// Opens a framebuffer exported into shared memory, e.g. under `/dev/shm`. Unlike xochitl's memory,
+/// this doesn't need ptrace-level permissions.
+///
+/// The exporter starts the file with a line in framebuffer-spy's config format. Its address is the offset
+/// of the framebuffer within the file, after that line.
+pub fn open_shared_memory(
+    path: &Path,
+) -> Result<(FramebufferSource, FramebufferConfig, Option<Orientation>)> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut header = String::new();
+    BufReader::new(&file).read_line(&mut header)?;
+    let spy_config = FramebufferSpyConfig::parse(header.trim_end())
+        .with_context(|| format!("{} doesn't start with a framebuffer config", path.display()))?;
+    let rotation = spy_config.rotation;
+    let config = FramebufferConfig::try_from(spy_config)?;
+
+    let length = file.metadata()?.len() as usize;
+    if config.address < header.len() || config.address + config.fb_size > length {
+        bail!(
+            "The framebuffer doesn't fit into {} after its header",
+            path.display()
+        );
+    }
+    let shared = SharedMemory {
+        path: path.to_path_buf(),
+        file: Mutex::new(file),
+        header: header.into_bytes(),
+    };
+    Ok((FramebufferSource::SharedMemory(shared), config, rotation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_plain_files() {
+        let path = std::env::temp_dir().join(format!("rmstream-fb-{}", std::process::id()));
+        // Two rows of two RGB565 pixels, padded to three.
+        let contents = [
+            0x00, 0xF8, 0x1F, 0x00, 0xAA, 0xAA, //
+            0xFF, 0xFF, 0x00, 0x00, 0xAA, 0xAA,
+        ];
+        std::fs::write(&path, contents).unwrap();
+        let framebuffer = FramebufferFile {
+            path: path.to_string_lossy().into_owned(),
+            address: None,
+            width: Some(2),
+            height: Some(2),
+            stride: Some(6),
+            pixel_format: Some(PixelFormat::Rgb565),
+        };
+        let (source, config) = open_framebuffer_file(&framebuffer).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut data = vec![0u8; config.fb_size];
+        source.read_rows(&config, &mut data, 1..2).unwrap();
+        assert_eq!(data[..6], [0; 6]);
+        source
+            .read_rows(&config, &mut data, 0..config.height)
+            .unwrap();
+        assert_eq!(data, contents);
+        let mut rgba = vec![0u8; 2 * 2 * 4];
+        config.translate_rows(&data, &mut rgba, 0..config.height);
+        assert_eq!(
+            rgba,
+            [255, 0, 0, 255, 0, 0, 255, 255, 255, 255, 255, 255, 0, 0, 0, 255]
+        );
+    }
+
+    #[test]
+    fn plain_files_need_a_geometry() {
+        let framebuffer = FramebufferFile {
+            path: "/dev/null".to_string(),
+            address: None,
+            width: None,
+            height: Some(2),
+            stride: None,
+            pixel_format: Some(PixelFormat::Rgb565),
+        };
+        assert!(open_framebuffer_file(&framebuffer).is_err());
+    }
+
+    #[test]
+    fn sees_what_the_exporter_writes() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let path = std::env::temp_dir().join(format!("rmstream-shm-{}", std::process::id()));
+        // A 2x1 RGB565 framebuffer, right after the header.
+        let header = "0x20,2,1,1,4,0\n";
+        let mut contents = header.as_bytes().to_vec();
+        contents.resize(0x20 + 4, 0);
+        std::fs::write(&path, &contents).unwrap();
+        let (source, config, rotation) = open_shared_memory(&path).unwrap();
+        assert_eq!(rotation, None);
+
+        // The exporter keeps writing into the file after it's mapped.
+        let mut writer = OpenOptions::new().write(true).open(&path).unwrap();
+        writer.seek(SeekFrom::Start(0x20)).unwrap();
+        writer.write_all(&[0x00, 0xF8, 0xFF, 0xFF]).unwrap();
+        drop(writer);
+
+        let mut data = vec![0u8; config.fb_size];
+        source
+            .read_rows(&config, &mut data, 0..config.height)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data, [0x00, 0xF8, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn survives_the_exporter_restarting() {
+        let path =
+            std::env::temp_dir().join(format!("rmstream-shm-restart-{}", std::process::id()));
+        let mut contents = b"0x20,2,1,1,4,0\n".to_vec();
+        contents.resize(0x20 + 4, 0xAA);
+        std::fs::write(&path, &contents).unwrap();
+        let (source, config, _) = open_shared_memory(&path).unwrap();
+        let mut data = vec![0u8; config.fb_size];
+
+        // Truncated while restarting.
+        std::fs::write(&path, &contents[..0x20]).unwrap();
+        assert!(source.read_rows(&config, &mut data, 0..1).is_err());
+
+        // Replaced by a new file with the same layout.
+        let replacement = path.with_extension("new");
+        contents[0x20..].copy_from_slice(&[1, 2, 3, 4]);
+        std::fs::write(&replacement, &contents).unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        source.read_rows(&config, &mut data, 0..1).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+
+        // Back with another geometry.
+        contents[..15].copy_from_slice(b"0x20,1,2,1,2,0\n");
+        std::fs::write(&path, &contents).unwrap();
+        assert!(source.read_rows(&config, &mut data, 0..1).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
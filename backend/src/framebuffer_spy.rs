@@ -1,53 +1,242 @@
-use std::{fmt::Display, num::ParseIntError};
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::orientation::Orientation;
+use crate::pixel_format::PixelFormat;
+
+/// The newest config version this parser knows. Newer versions are still read, as their unknown fields
+/// are skipped.
+const LATEST_VERSION: u32 = 2;
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct FramebufferSpyConfig {
     pub address: usize,
     pub width: u32,
     pub height: u32,
+    /// framebuffer-spy's pixel format type id. 0 if the format is given by name instead.
     pub r#type: u32,
+    /// The pixel format given by name, in configs after version 1. Takes precedence over `r#type`.
+    pub pixel_format: Option<PixelFormat>,
     pub bpl: u32,
     pub requires_reload: bool,
+    /// How far the screen's contents are rotated clockwise, if reported.
+    pub rotation: Option<Orientation>,
+    /// Where the visible part starts within the buffer, in pixels. It ends with the buffer.
+    pub x_offset: u32,
+    pub y_offset: u32,
 }
 
-#[derive(Debug)]
-pub struct FramebufferSpyConfigParsingError;
-impl From<ParseIntError> for FramebufferSpyConfigParsingError {
-    fn from(_: ParseIntError) -> Self {
-        Self
-    }
+#[derive(Debug, PartialEq)]
+pub enum FramebufferSpyConfigParsingError {
+    /// framebuffer-spy didn't answer, so it's most likely not installed.
+    Missing,
+    /// A version 1 config which doesn't have 6 or 7 fields.
+    FieldCount(usize),
+    MissingField(&'static str),
+    InvalidField {
+        field: String,
+        value: String,
+        reason: String,
+    },
 }
+
 impl std::error::Error for FramebufferSpyConfigParsingError {}
 impl Display for FramebufferSpyConfigParsingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "FramebufferSpyParsingError")
+        match self {
+            Self::Missing => write!(f, "framebuffer-spy didn't report a config"),
+            Self::FieldCount(count) => write!(f, "expected 6 or 7 fields, got {count}"),
+            Self::MissingField(field) => write!(f, "the {field} field is missing"),
+            Self::InvalidField {
+                field,
+                value,
+                reason,
+            } => write!(f, "invalid {field} \"{value}\": {reason}"),
+        }
+    }
+}
+
+pub fn invalid_field(
+    field: &str,
+    value: &str,
+    reason: impl Display,
+) -> FramebufferSpyConfigParsingError {
+    FramebufferSpyConfigParsingError::InvalidField {
+        field: field.to_string(),
+        value: value.to_string(),
+        reason: reason.to_string(),
     }
 }
+
+fn parse_field<T: FromStr>(field: &str, value: &str) -> Result<T, FramebufferSpyConfigParsingError>
+where
+    T::Err: Display,
+{
+    value.parse().map_err(|e| invalid_field(field, value, e))
+}
+
+fn parse_address(value: &str) -> Result<usize, FramebufferSpyConfigParsingError> {
+    let hex = value
+        .strip_prefix("0x")
+        .ok_or_else(|| invalid_field("address", value, "it has to start with 0x"))?;
+    usize::from_str_radix(hex, 16).map_err(|e| invalid_field("address", value, e))
+}
+
+fn parse_rotation(value: &str) -> Result<Orientation, FramebufferSpyConfigParsingError> {
+    Orientation::from_degrees(parse_field("rotation", value)?)
+        .ok_or_else(|| invalid_field("rotation", value, "it has to be 0, 90, 180 or 270"))
+}
+
 impl FramebufferSpyConfig {
+    /// Parses the config string framebuffer-spy reports.
+    ///
+    /// Version 1 is positional: `0x<address>,<width>,<height>,<type>,<bpl>,<requires reload>[,<rotation>]`.
+    /// Later versions start with `v<version>`, followed by `key=value` fields: `address`, `width`, `height`,
+    /// `type` or `format` (a pixel format name), `bpl` or `stride`, `reload`, `rotation`, and `offset`
+    /// (`<x>x<y>`, where the visible part starts). Unknown keys are skipped.
     pub fn parse(string: &str) -> Result<Self, FramebufferSpyConfigParsingError> {
-        let tokens = string.split(",").collect::<Vec<_>>();
-        if tokens.len() != 6 {
-            Err(FramebufferSpyConfigParsingError)
-        } else {
-            let s_fb_addr = tokens[0];
-            let s_width = tokens[1];
-            let s_height = tokens[2];
-            let s_type = tokens[3];
-            let s_bpl = tokens[4];
-            let s_requires_reload = tokens[5];
-            if !s_fb_addr.starts_with("0x") {
-                Err(FramebufferSpyConfigParsingError)
-            } else {
-                Ok(Self {
-                    address: usize::from_str_radix(&s_fb_addr[2..s_fb_addr.len()], 16)?,
-                    width: s_width.parse()?,
-                    height: s_height.parse()?,
-                    r#type: s_type.parse()?,
-                    bpl: s_bpl.parse()?,
-                    requires_reload: s_requires_reload == "1",
-                })
+        let string = string.trim();
+        if string.is_empty() {
+            return Err(FramebufferSpyConfigParsingError::Missing);
+        }
+        let tokens = string.split(',').map(str::trim).collect::<Vec<_>>();
+        match tokens[0].strip_prefix('v') {
+            Some(version) => {
+                let version: u32 = parse_field("version", version)?;
+                if version < 2 {
+                    return Err(invalid_field(
+                        "version",
+                        tokens[0],
+                        "version 1 configs aren't prefixed",
+                    ));
+                }
+                if version > LATEST_VERSION {
+                    eprintln!("framebuffer-spy config version {version} is newer than this backend. Reading the fields it knows.");
+                }
+                Self::parse_fields(&tokens[1..])
             }
+            None => Self::parse_positional(&tokens),
         }
     }
+
+    fn parse_positional(tokens: &[&str]) -> Result<Self, FramebufferSpyConfigParsingError> {
+        // The rotation is an optional seventh token.
+        if tokens.len() != 6 && tokens.len() != 7 {
+            return Err(FramebufferSpyConfigParsingError::FieldCount(tokens.len()));
+        }
+        Ok(Self {
+            address: parse_address(tokens[0])?,
+            width: parse_field("width", tokens[1])?,
+            height: parse_field("height", tokens[2])?,
+            r#type: parse_field("type", tokens[3])?,
+            bpl: parse_field("bpl", tokens[4])?,
+            pixel_format: None,
+            requires_reload: tokens[5] == "1",
+            rotation: tokens.get(6).copied().map(parse_rotation).transpose()?,
+            x_offset: 0,
+            y_offset: 0,
+        })
+    }
+
+    fn parse_fields(tokens: &[&str]) -> Result<Self, FramebufferSpyConfigParsingError> {
+        let mut address = None;
+        let mut width = 0;
+        let mut height = None;
+        let mut r#type = None;
+        let mut pixel_format = None;
+        let mut bpl = None;
+        let mut requires_reload = false;
+        let mut rotation = None;
+        let (mut x_offset, mut y_offset) = (0, 0);
+        for token in tokens {
+            let (key, value) = token
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| invalid_field("field", token, "expected key=value"))?;
+            match key {
+                "address" => address = Some(parse_address(value)?),
+                "width" => width = parse_field(key, value)?,
+                "height" => height = Some(parse_field(key, value)?),
+                "type" => r#type = Some(parse_field(key, value)?),
+                "format" => {
+                    pixel_format = Some(
+                        PixelFormat::from_name(value)
+                            .ok_or_else(|| invalid_field(key, value, "unknown pixel format"))?,
+                    );
+                }
+                "bpl" | "stride" => bpl = Some(parse_field(key, value)?),
+                "reload" => requires_reload = value == "1",
+                "rotation" => rotation = Some(parse_rotation(value)?),
+                "offset" => {
+                    let (x, y) = value
+                        .split_once('x')
+                        .ok_or_else(|| invalid_field(key, value, "expected <x>x<y>"))?;
+                    x_offset = parse_field(key, x)?;
+                    y_offset = parse_field(key, y)?;
+                }
+                _ => {}
+            }
+        }
+        use FramebufferSpyConfigParsingError::MissingField;
+        Ok(Self {
+            address: address.ok_or(MissingField("address"))?,
+            width,
+            height: height.ok_or(MissingField("height"))?,
+            r#type: match (r#type, pixel_format) {
+                (Some(r#type), _) => r#type,
+                (None, Some(_)) => 0,
+                (None, None) => return Err(MissingField("format")),
+            },
+            pixel_format,
+            bpl: bpl.ok_or(MissingField("stride"))?,
+            requires_reload,
+            rotation,
+            x_offset,
+            y_offset,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_version_1() {
+        let config = FramebufferSpyConfig::parse("0x1000,1620,2160,1,3240,1,90").unwrap();
+        assert_eq!(config.address, 0x1000);
+        assert_eq!(
+            (config.width, config.height, config.bpl),
+            (1620, 2160, 3240)
+        );
+        assert_eq!(config.rotation, Some(Orientation::Clockwise90));
+        assert!(config.requires_reload);
+    }
+
+    #[test]
+    fn parses_later_versions() {
+        let config = FramebufferSpyConfig::parse(
+            "v2,address=0x20,height=2,format=y8,stride=8,offset=2x1,unknown=1",
+        )
+        .unwrap();
+        assert_eq!(config.address, 0x20);
+        assert_eq!(config.pixel_format, Some(PixelFormat::Y8));
+        assert_eq!((config.x_offset, config.y_offset), (2, 1));
+        assert_eq!(config.width, 0);
+    }
+
+    #[test]
+    fn reports_what_failed() {
+        use FramebufferSpyConfigParsingError::*;
+        assert_eq!(FramebufferSpyConfig::parse(""), Err(Missing));
+        assert_eq!(FramebufferSpyConfig::parse("0x0,1,2"), Err(FieldCount(3)));
+        assert_eq!(
+            FramebufferSpyConfig::parse("v2,address=0x0,height=1,type=1"),
+            Err(MissingField("stride"))
+        );
+        let error = FramebufferSpyConfig::parse("0x0,1,two,1,2,0").unwrap_err();
+        assert!(matches!(error, InvalidField { ref field, .. } if field == "height"));
+        assert!(error.to_string().starts_with("invalid height \"two\""));
+    }
 }
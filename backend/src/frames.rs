@@ -0,0 +1,440 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use tokio::sync::OnceCell;
+
+use crate::codec::CodecSettings;
+use crate::color_mode::ColorMode;
+
+pub const DELTA_PACKET: u8 = 1;
+pub const PNG_KEYFRAME_PACKET: u8 = 3;
+/// Keyframe in any format other than PNG: the packet type, the format id, then the encoded image.
+pub const KEYFRAME_PACKET: u8 = 5;
+/// Part of a larger packet: the packet type, a byte set to 1 on the last chunk, then the data.
+/// Clients concatenate the data of all chunks and handle the result as a single packet.
+pub const CHUNK_PACKET: u8 = 8;
+/// Frames larger than this are split for clients which accept chunks,
+/// so that pointer packets can be sent in between.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Splits the packet into `CHUNK_PACKET`s. Packets which fit in a single chunk are left as they are.
+pub fn split_into_chunks(packet: Vec<u8>) -> Vec<Vec<u8>> {
+    if packet.len() <= CHUNK_SIZE {
+        return vec![packet];
+    }
+    let count = packet.len().div_ceil(CHUNK_SIZE);
+    packet
+        .chunks(CHUNK_SIZE)
+        .enumerate()
+        .map(|(i, data)| {
+            let mut chunk = Vec::with_capacity(data.len() + 2);
+            chunk.push(CHUNK_PACKET);
+            chunk.push(u8::from(i + 1 == count));
+            chunk.extend_from_slice(data);
+            chunk
+        })
+        .collect()
+}
+
+struct ImageDelta {
+    offset: u32,
+    data: Vec<u8>,
+}
+
+impl ImageDelta {
+    fn serialize(&self) -> Vec<u8> {
+        let mut outbound = Vec::with_capacity(self.data.len() + 4 * 2);
+        outbound.extend_from_slice(&self.offset.to_be_bytes());
+        outbound.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        outbound.extend_from_slice(&self.data);
+
+        outbound
+    }
+}
+
+/// Serializes the differences between the RGBA8888 frames `old` and `new` as a list of `ImageDelta`s.
+/// Every delta holds whole pixels, so that it can be converted into other colour modes.
+pub fn encode_deltas(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut deltas = Vec::new();
+    let mut current_delta = None;
+    for (i, (old, new)) in old.chunks_exact(4).zip(new.chunks_exact(4)).enumerate() {
+        match (old == new, current_delta.is_none()) {
+            (true, true) => {}
+            (false, true) => {
+                // There is a difference, and we're not in a delta. => Create a new delta
+                current_delta = Some(ImageDelta {
+                    offset: 4 * i as u32,
+                    data: new.to_vec(),
+                });
+            }
+            (true, false) => {
+                // There's no difference, and we're in a delta => Finish delta.
+                deltas.extend_from_slice(&current_delta.unwrap().serialize());
+                current_delta = None;
+            }
+            (false, false) => {
+                // No changes, and delta exists => Append to delta
+                current_delta.as_mut().unwrap().data.extend_from_slice(new);
+            }
+        }
+    }
+    // If there's a leftover delta, push it
+    if let Some(delta) = current_delta {
+        deltas.extend_from_slice(&delta.serialize());
+    }
+    deltas
+}
+
+/// The number of RGBA bytes the serialized deltas change, without their headers.
+pub fn changed_bytes(deltas: &[u8]) -> usize {
+    let mut changed = 0;
+    let mut cursor = 0;
+    while cursor + 8 <= deltas.len() {
+        let length =
+            u32::from_be_bytes(deltas[cursor + 4..cursor + 8].try_into().unwrap()) as usize;
+        changed += length;
+        cursor += 8 + length;
+    }
+    changed
+}
+
+/// Converts RGBA8888 deltas into `color_mode`, where offsets and lengths count pixels,
+/// and palette indices are packed.
+fn convert_deltas(deltas: &[u8], color_mode: ColorMode) -> Vec<u8> {
+    if color_mode == ColorMode::Color {
+        return deltas.to_vec();
+    }
+    let mut converted = Vec::with_capacity(deltas.len() / 4 + 8);
+    let mut cursor = 0;
+    while cursor + 8 <= deltas.len() {
+        let u32_at = |i: usize| u32::from_be_bytes(deltas[i..i + 4].try_into().unwrap());
+        let (offset, length) = (u32_at(cursor), u32_at(cursor + 4));
+        let data = &deltas[cursor + 8..cursor + 8 + length as usize];
+        converted.extend_from_slice(&(offset / 4).to_be_bytes());
+        converted.extend_from_slice(&(length / 4).to_be_bytes());
+        converted.extend_from_slice(&color_mode.pack(&color_mode.convert(data)));
+        cursor += 8 + length as usize;
+    }
+    converted
+}
+
+type EncodedPackets<K> = Vec<(K, Arc<OnceCell<Vec<u8>>>)>;
+
+/// Encoded variants of a frame. Each variant is encoded at most once, no matter how many clients use it.
+struct PacketCache<K> {
+    packets: std::sync::Mutex<EncodedPackets<K>>,
+}
+
+impl<K> Default for PacketCache<K> {
+    fn default() -> Self {
+        Self {
+            packets: Default::default(),
+        }
+    }
+}
+
+impl<K: PartialEq + Copy> PacketCache<K> {
+    async fn get_or_encode(&self, key: K, encode: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        let cell = {
+            let mut packets = self.packets.lock().unwrap();
+            match packets.iter().find(|e| e.0 == key) {
+                Some((_, cell)) => cell.clone(),
+                None => {
+                    let cell = Arc::new(OnceCell::new());
+                    packets.push((key, cell.clone()));
+                    cell
+                }
+            }
+        };
+        cell.get_or_init(|| async { encode() }).await.clone()
+    }
+}
+
+/// A set of serialized `ImageDelta`s, converted and compressed lazily for each client's colour mode and codec.
+pub struct DeltaFrame {
+    deltas: Vec<u8>,
+    packets: PacketCache<(CodecSettings, ColorMode)>,
+}
+
+impl DeltaFrame {
+    pub fn new(deltas: Vec<u8>) -> Self {
+        Self {
+            deltas,
+            packets: Default::default(),
+        }
+    }
+
+    pub async fn packet(&self, codec: CodecSettings, color_mode: ColorMode) -> Vec<u8> {
+        self.packets
+            .get_or_encode((codec, color_mode), || {
+                let deltas = convert_deltas(&self.deltas, color_mode);
+                let mut packet = vec![DELTA_PACKET];
+                packet.extend_from_slice(&(deltas.len() as u32).to_be_bytes());
+                packet.extend_from_slice(&codec.compress(&deltas));
+                packet
+            })
+            .await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyframeFormat {
+    Png,
+    /// Always RGBA, as QOI has no grayscale or indexed images. In other colour modes, the reduced colours
+    /// are expanded back, so the keyframe is as large as in `ColorMode::Color`.
+    Qoi,
+    /// Every byte minus the same byte of the previous pixel, compressed with the client's codec.
+    /// Each pixel is as many bytes as the client's colour mode uses. Palette indices smaller than a byte
+    /// are packed first, every row starting on a new byte like in PNG, and filtered byte by byte.
+    FilteredRaw,
+}
+
+impl KeyframeFormat {
+    pub const ALL: [KeyframeFormat; 3] = [
+        KeyframeFormat::Png,
+        KeyframeFormat::Qoi,
+        KeyframeFormat::FilteredRaw,
+    ];
+
+    pub fn id(self) -> u8 {
+        match self {
+            KeyframeFormat::Png => 0,
+            KeyframeFormat::Qoi => 1,
+            KeyframeFormat::FilteredRaw => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.id() == id)
+    }
+}
+
+impl FromStr for KeyframeFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(KeyframeFormat::Png),
+            "qoi" => Ok(KeyframeFormat::Qoi),
+            "raw" => Ok(KeyframeFormat::FilteredRaw),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A snapshot of the whole screen, encoded lazily in each client's keyframe format and colour mode.
+pub struct Keyframe {
+    image: Vec<u8>,
+    width: u32,
+    height: u32,
+    packets: PacketCache<(KeyframeFormat, Option<CodecSettings>, ColorMode)>,
+}
+
+impl Keyframe {
+    pub fn new(image: Vec<u8>, width: u32, height: u32) -> Self {
+        Self {
+            image,
+            width,
+            height,
+            packets: Default::default(),
+        }
+    }
+
+    pub async fn packet(
+        &self,
+        format: KeyframeFormat,
+        codec: CodecSettings,
+        color_mode: ColorMode,
+    ) -> Vec<u8> {
+        // Only the raw format goes through the codec.
+        let codec = (format == KeyframeFormat::FilteredRaw).then_some(codec);
+        self.packets
+            .get_or_encode((format, codec, color_mode), || {
+                let image = color_mode.convert(&self.image);
+                match format {
+                    KeyframeFormat::Png => self.encode_png(&image, color_mode),
+                    KeyframeFormat::Qoi => self.encode_qoi(&image, color_mode),
+                    KeyframeFormat::FilteredRaw => {
+                        self.encode_filtered_raw(&image, color_mode, codec.unwrap())
+                    }
+                }
+            })
+            .await
+    }
+
+    fn encode_png(&self, image: &[u8], color_mode: ColorMode) -> Vec<u8> {
+        let mut out = vec![PNG_KEYFRAME_PACKET];
+        let mut encoder = png::Encoder::new(&mut out, self.width, self.height);
+        let mut depth = png::BitDepth::Eight;
+        match color_mode {
+            ColorMode::Color => encoder.set_color(png::ColorType::Rgba),
+            ColorMode::Grayscale => encoder.set_color(png::ColorType::Grayscale),
+            ColorMode::Palette { index_bits } => {
+                encoder.set_color(png::ColorType::Indexed);
+                encoder.set_palette(color_mode.palette().unwrap().rgb_bytes());
+                depth = png::BitDepth::from_u8(index_bits).unwrap();
+            }
+        }
+        encoder.set_depth(depth);
+        let image = color_mode.pack_rows(image, self.width);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&image)
+            .unwrap();
+        out
+    }
+
+    fn encode_qoi(&self, image: &[u8], color_mode: ColorMode) -> Vec<u8> {
+        // QOI only knows about RGB(A), so the reduced image is expanded back.
+        let image = color_mode.expand(image);
+        let mut out = vec![KEYFRAME_PACKET, KeyframeFormat::Qoi.id()];
+        out.extend_from_slice(&qoi::encode_to_vec(&image, self.width, self.height).unwrap());
+        out
+    }
+
+    fn encode_filtered_raw(
+        &self,
+        image: &[u8],
+        color_mode: ColorMode,
+        codec: CodecSettings,
+    ) -> Vec<u8> {
+        let pixel_size = color_mode.bytes_per_pixel();
+        let image = color_mode.pack_rows(image, self.width);
+        let filtered = image
+            .iter()
+            .take(pixel_size)
+            .copied()
+            .chain(
+                image
+                    .iter()
+                    .zip(&image[pixel_size..])
+                    .map(|(previous, current)| current.wrapping_sub(*previous)),
+            )
+            .collect::<Vec<_>>();
+        let mut out = vec![KEYFRAME_PACKET, KeyframeFormat::FilteredRaw.id()];
+        out.extend_from_slice(&(filtered.len() as u32).to_be_bytes());
+        out.extend_from_slice(&codec.compress(&filtered));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Codec;
+
+    fn reassemble(chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunks.iter().flat_map(|e| e[2..].to_vec()).collect()
+    }
+
+    #[test]
+    fn leaves_small_packets_whole() {
+        for size in [0, 1, CHUNK_SIZE] {
+            let packet = vec![DELTA_PACKET; size];
+            assert_eq!(split_into_chunks(packet.clone()), [packet]);
+        }
+    }
+
+    #[test]
+    fn splits_large_packets_into_chunks() {
+        for (size, count) in [
+            (CHUNK_SIZE + 1, 2),
+            (2 * CHUNK_SIZE, 2),
+            (2 * CHUNK_SIZE + 1, 3),
+        ] {
+            let packet = (0..size).map(|i| i as u8).collect::<Vec<_>>();
+            let chunks = split_into_chunks(packet.clone());
+            assert_eq!(chunks.len(), count, "{size}");
+            assert!(chunks.iter().all(|e| e[0] == CHUNK_PACKET));
+            assert!(chunks.iter().all(|e| e.len() <= CHUNK_SIZE + 2));
+            // Only the last chunk is flagged.
+            let flags = chunks.iter().map(|e| e[1]).collect::<Vec<_>>();
+            assert_eq!(flags[..count - 1], vec![0; count - 1]);
+            assert_eq!(flags[count - 1], 1);
+            assert_eq!(reassemble(&chunks), packet);
+        }
+    }
+
+    /// Two 4-pixel RGBA frames differing in pixels 1 and 3.
+    fn frames() -> ([u8; 16], [u8; 16]) {
+        let old = [0u8; 16];
+        let mut new = old;
+        new[4..8].copy_from_slice(&[255, 255, 255, 255]);
+        new[12..16].copy_from_slice(&[255, 0, 0, 255]);
+        (old, new)
+    }
+
+    #[test]
+    fn counts_changed_bytes_without_headers() {
+        let (old, new) = frames();
+        let deltas = encode_deltas(&old, &new);
+        assert_eq!(deltas.len(), 2 * (8 + 4));
+        assert_eq!(changed_bytes(&deltas), 8);
+    }
+
+    #[test]
+    fn converts_deltas_into_pixel_offsets() {
+        let (old, new) = frames();
+        let deltas = encode_deltas(&old, &new);
+        assert_eq!(
+            convert_deltas(&deltas, ColorMode::Grayscale),
+            [0, 0, 0, 1, 0, 0, 0, 1, 255, 0, 0, 0, 3, 0, 0, 0, 1, 76]
+        );
+        // Outside of a reMarkable, the palette is the default 16 grays. Red is closest to gray 5.
+        assert_eq!(
+            convert_deltas(&deltas, ColorMode::Palette { index_bits: 4 }),
+            [0, 0, 0, 1, 0, 0, 0, 1, 0xF0, 0, 0, 0, 3, 0, 0, 0, 1, 0x50]
+        );
+        assert_eq!(convert_deltas(&deltas, ColorMode::Color), deltas);
+    }
+
+    /// A 400x300 page of short dark strokes in a few gray levels on white, like handwriting.
+    fn handwritten_page() -> Keyframe {
+        let (width, height) = (400u32, 300u32);
+        let mut image = vec![255u8; (width * height * 4) as usize];
+        let mut seed = 12345u32;
+        let mut random = |limit: u32| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) % limit
+        };
+        for _ in 0..3000 {
+            let (x, y, length) = (random(width - 20), random(height), 2 + random(18));
+            let gray = [0, 0, 0, 85, 170][random(5) as usize];
+            for x in x..x + length {
+                let i = ((y * width + x) * 4) as usize;
+                image[i..i + 3].copy_from_slice(&[gray; 3]);
+            }
+        }
+        Keyframe::new(image, width, height)
+    }
+
+    /// On this page, compared to RGBA: 1-bit PNG keyframes are about 3 times smaller and 2-bit ones 2 times.
+    /// Filtered raw keyframes and deflated deltas only shrink by 1.2 - 1.5 times, as deflate already
+    /// makes short work of the repeated RGBA bytes.
+    #[test]
+    fn packed_indices_are_smaller_than_rgba() {
+        let page = handwritten_page();
+        let codec = CodecSettings::new(Codec::Deflate, None);
+        let (one_bit, two_bits) = (
+            ColorMode::Palette { index_bits: 1 },
+            ColorMode::Palette { index_bits: 2 },
+        );
+        let png = |color_mode: ColorMode| {
+            page.encode_png(&color_mode.convert(&page.image), color_mode)
+                .len()
+        };
+        let raw = |color_mode: ColorMode| {
+            page.encode_filtered_raw(&color_mode.convert(&page.image), color_mode, codec)
+                .len()
+        };
+        let delta = |color_mode: ColorMode| {
+            let deltas = encode_deltas(&vec![255u8; page.image.len()], &page.image);
+            codec.compress(&convert_deltas(&deltas, color_mode)).len()
+        };
+        assert!(png(one_bit) * 5 < png(ColorMode::Color) * 2);
+        assert!(png(two_bits) * 3 < png(ColorMode::Color) * 2);
+        assert!(raw(two_bits) < raw(ColorMode::Color));
+        assert!(delta(two_bits) < delta(ColorMode::Color));
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::codec::{Codec, CodecSettings};
+use crate::color_mode::{ColorMode, INDEX_BITS};
+use crate::frames::KeyframeFormat;
+use crate::settings::SETTINGS;
+
+/// Message sent by the client to pick its options.
+pub const CLIENT_HELLO: u8 = 0;
+/// Packet sent by the server to confirm the options it settled on for a client.
+pub const SESSION_PACKET: u8 = 4;
+
+pub const OPTION_CODEC: u8 = 1;
+pub const OPTION_CODEC_LEVEL: u8 = 2;
+pub const OPTION_KEYFRAME_FORMAT: u8 = 3;
+pub const OPTION_PRECISE_POINTER: u8 = 4;
+pub const OPTION_TOUCH: u8 = 5;
+pub const OPTION_CHUNKED_FRAMES: u8 = 6;
+pub const OPTION_STROKES: u8 = 7;
+pub const OPTION_COLOR_MODE: u8 = 8;
+/// The size of the palette indices in `ColorMode::Palette`.
+pub const OPTION_INDEX_BITS: u8 = 9;
+
+/// Per-client stream options.
+///
+/// After the config packet, the client may answer with a hello packet:
+/// a `CLIENT_HELLO` byte followed by any number of (option id: u8, value: i32 BE) pairs.
+/// Unknown options are ignored, and unsupported values fall back to the defaults.
+/// The server then confirms the options in effect with a `SESSION_PACKET` of the same layout.
+/// Clients which don't send a hello get the defaults, and no `SESSION_PACKET`. A hello arriving after the first
+/// keyframe is still applied: the server then confirms it and sends a new keyframe in the chosen options.
+pub struct ClientOptions {
+    pub codec: CodecSettings,
+    pub keyframe_format: KeyframeFormat,
+    /// Whether to send `POINTER_PACKET`s instead of `LEGACY_POINTER_PACKET`s.
+    pub precise_pointer: bool,
+    /// Whether to send `TOUCH_PACKET`s.
+    pub touch: bool,
+    /// Whether large frames can be split into `CHUNK_PACKET`s.
+    pub chunked_frames: bool,
+    /// Whether to send `STROKE_PACKET`s.
+    pub strokes: bool,
+    pub color_mode: ColorMode,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            codec: SETTINGS.default_codec,
+            keyframe_format: SETTINGS.default_keyframe_format,
+            precise_pointer: false,
+            touch: false,
+            chunked_frames: false,
+            strokes: false,
+            color_mode: ColorMode::Color,
+        }
+    }
+}
+
+/// The options deciding how frames are encoded for a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameFormat {
+    pub codec: CodecSettings,
+    pub keyframe_format: KeyframeFormat,
+    pub color_mode: ColorMode,
+}
+
+lazy_static! {
+    /// The frame format of every connected client, once per client.
+    static ref CONNECTED_FORMATS: Mutex<Vec<FrameFormat>> = Mutex::new(Vec::new());
+}
+
+/// Keeps a client's frame format among the `connected_formats` until it's dropped.
+pub struct FormatRegistration(FrameFormat);
+
+impl FormatRegistration {
+    pub fn new(format: FrameFormat) -> Self {
+        CONNECTED_FORMATS.lock().unwrap().push(format);
+        Self(format)
+    }
+
+    /// Replaces the client's format, after it picked new options.
+    pub fn update(&mut self, format: FrameFormat) {
+        let mut formats = CONNECTED_FORMATS.lock().unwrap();
+        if let Some(index) = formats.iter().position(|e| *e == self.0) {
+            formats[index] = format;
+        }
+        self.0 = format;
+    }
+}
+
+impl Drop for FormatRegistration {
+    fn drop(&mut self) {
+        let mut formats = CONNECTED_FORMATS.lock().unwrap();
+        if let Some(index) = formats.iter().position(|e| *e == self.0) {
+            formats.swap_remove(index);
+        }
+    }
+}
+
+/// The distinct frame formats connected clients use.
+pub fn connected_formats() -> Vec<FrameFormat> {
+    let mut formats = Vec::new();
+    for format in CONNECTED_FORMATS.lock().unwrap().iter() {
+        if !formats.contains(format) {
+            formats.push(*format);
+        }
+    }
+    formats
+}
+
+impl ClientOptions {
+    pub fn frame_format(&self) -> FrameFormat {
+        FrameFormat {
+            codec: self.codec,
+            keyframe_format: self.keyframe_format,
+            color_mode: self.color_mode,
+        }
+    }
+
+    pub fn parse_hello(data: &[u8]) -> Self {
+        let mut options = Self::default();
+        if data.first() != Some(&CLIENT_HELLO) {
+            return options;
+        }
+        let mut codec = None;
+        let mut level = None;
+        let mut index_bits = None;
+        for pair in data[1..].chunks_exact(5) {
+            let value = i32::from_be_bytes([pair[1], pair[2], pair[3], pair[4]]);
+            match pair[0] {
+                OPTION_CODEC => codec = u8::try_from(value).ok().and_then(Codec::from_id),
+                OPTION_CODEC_LEVEL => level = Some(value),
+                OPTION_KEYFRAME_FORMAT => {
+                    if let Some(format) = u8::try_from(value).ok().and_then(KeyframeFormat::from_id)
+                    {
+                        options.keyframe_format = format;
+                    }
+                }
+                OPTION_PRECISE_POINTER => options.precise_pointer = value != 0,
+                OPTION_TOUCH => options.touch = value != 0,
+                OPTION_CHUNKED_FRAMES => options.chunked_frames = value != 0,
+                OPTION_STROKES => options.strokes = value != 0,
+                OPTION_INDEX_BITS => {
+                    index_bits = u8::try_from(value).ok().filter(|e| INDEX_BITS.contains(e))
+                }
+                OPTION_COLOR_MODE => {
+                    if let Some(color_mode) = u8::try_from(value).ok().and_then(ColorMode::from_id)
+                    {
+                        options.color_mode = color_mode;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(codec) = codec {
+            // Levels are only meaningful for the codec they were requested with.
+            options.codec = CodecSettings::new(codec, level);
+        } else if let Some(level) = level {
+            options.codec = CodecSettings::new(options.codec.codec, Some(level));
+        }
+        if let (ColorMode::Palette { .. }, Some(index_bits)) = (options.color_mode, index_bits) {
+            options.color_mode = ColorMode::Palette { index_bits };
+        }
+        options
+    }
+
+    pub fn serialize_session_packet(&self) -> Vec<u8> {
+        let mut packet = vec![SESSION_PACKET];
+        for (option, value) in [
+            (OPTION_CODEC, i32::from(self.codec.codec.id())),
+            (OPTION_CODEC_LEVEL, self.codec.level),
+            (OPTION_KEYFRAME_FORMAT, i32::from(self.keyframe_format.id())),
+            (OPTION_PRECISE_POINTER, i32::from(self.precise_pointer)),
+            (OPTION_TOUCH, i32::from(self.touch)),
+            (OPTION_CHUNKED_FRAMES, i32::from(self.chunked_frames)),
+            (OPTION_STROKES, i32::from(self.strokes)),
+            (OPTION_COLOR_MODE, i32::from(self.color_mode.id())),
+            (
+                OPTION_INDEX_BITS,
+                match self.color_mode {
+                    ColorMode::Palette { index_bits } => i32::from(index_bits),
+                    ColorMode::Color | ColorMode::Grayscale => 8,
+                },
+            ),
+        ] {
+            packet.push(option);
+            packet.extend_from_slice(&value.to_be_bytes());
+        }
+        packet
+    }
+}
+
+/// Appends the list of supported option values to the config packet:
+/// an option count, followed by (option id: u8, value count: u8, values: u8...) for each option.
+/// Every keyframe format works with every colour mode, but QOI keyframes stay RGBA in all of them.
+pub fn serialize_capabilities(packet: &mut Vec<u8>) {
+    let capabilities: [(u8, Vec<u8>); 8] = [
+        (
+            OPTION_CODEC,
+            Codec::ALL.into_iter().map(Codec::id).collect(),
+        ),
+        (
+            OPTION_KEYFRAME_FORMAT,
+            KeyframeFormat::ALL
+                .into_iter()
+                .map(KeyframeFormat::id)
+                .collect(),
+        ),
+        (OPTION_PRECISE_POINTER, vec![0, 1]),
+        (OPTION_TOUCH, vec![0, 1]),
+        (OPTION_CHUNKED_FRAMES, vec![0, 1]),
+        (OPTION_STROKES, vec![0, 1]),
+        (
+            OPTION_COLOR_MODE,
+            ColorMode::ALL.into_iter().map(ColorMode::id).collect(),
+        ),
+        (OPTION_INDEX_BITS, INDEX_BITS.to_vec()),
+    ];
+    packet.push(capabilities.len() as u8);
+    for (option, values) in capabilities {
+        packet.push(option);
+        packet.push(values.len() as u8);
+        packet.extend_from_slice(&values);
+    }
+}
@@ -1,233 +1,344 @@
+mod calibration;
+mod codec;
+mod color_mode;
+mod damage;
 mod devices;
+mod framebuffer_source;
 mod framebuffer_spy;
-
-use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Cursor, Write};
-use std::os::fd::AsRawFd;
-use std::time::Duration;
-
-use anyhow::{bail, Result};
+mod frames;
+mod handshake;
+mod metrics;
+mod orientation;
+mod pixel_format;
+mod pointer;
+mod profiles;
+mod remote_input;
+mod settings;
+mod settle;
+mod stroke_export;
+mod strokes;
+mod touch;
+
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
 use appload_client::{
     AppLoad, AppLoadBackend, BackendReplier, Message, MSG_SYSTEM_NEW_COORDINATOR,
 };
 use async_trait::async_trait;
-use devices::{detect_device, get_device_info};
-use evdev::{AbsoluteAxisCode, Device, EventSummary, KeyCode, SynchronizationCode};
-use flate2::write::DeflateEncoder;
-use flate2::Compression;
 use futures::{SinkExt, StreamExt};
 use lazy_static::lazy_static;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{broadcast, Mutex};
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
 use warp::Filter;
 
-use crate::devices::FramebufferConfig;
-use crate::framebuffer_spy::FramebufferSpyConfig;
+use crate::calibration::{cancel_calibration, start_calibration, CalibrationInput};
+use crate::color_mode::ColorMode;
+use crate::damage::{clear_damage, next_damage, report_damage, CaptureMode};
+use crate::devices::{detect_device, FramebufferConfig};
+use crate::framebuffer_source::{open_framebuffer_file, open_shared_memory, FramebufferSource};
+use crate::framebuffer_spy::{FramebufferSpyConfig, FramebufferSpyConfigParsingError};
+use crate::frames::{changed_bytes, encode_deltas, split_into_chunks, DeltaFrame, Keyframe};
+use crate::handshake::{
+    connected_formats, serialize_capabilities, ClientOptions, FormatRegistration, FrameFormat,
+    CLIENT_HELLO,
+};
+use crate::metrics::render_metrics;
+use crate::orientation::{current_orientation, detect_orientation, Orientation};
+use crate::pointer::{update_pointer_pos_forever, PointerState};
+use crate::remote_input::{
+    answer_approval, request_approval, RemoteInput, CLIENT_REMOTE_INPUT_EVENT,
+    CLIENT_REMOTE_INPUT_REQUEST, REMOTE_INPUT_PACKET,
+};
+use crate::settings::SETTINGS;
+use crate::settle::Settler;
+use crate::stroke_export::{export_session, render_inkml, render_svg, PAGE_SIZE};
+use crate::touch::update_touch_forever;
 
 const SCREEN_POLL_RATE: Duration = Duration::from_millis(20);
-const DELTA_PNG_THRESHOLD: usize = 1_200_000;
-const SLEEP_AFTER_PNG_TRANSMISSION: Duration = Duration::from_millis(1500);
 const PORT: u16 = 3000;
+/// How long to wait for a client's hello before the first keyframe is sent with the default options.
+/// Clients without codec negotiation get their first frame this much later. A hello arriving later is
+/// still applied, at the cost of a second keyframe.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(150);
+
+#[derive(Clone)]
+enum Packet {
+    /// Deltas or a keyframe, already encoded for each format connected clients used when it was broadcast.
+    /// Only the encoded packets are kept, as slow clients hold on to every frame they haven't read yet.
+    Frame(Arc<Vec<(FrameFormat, Vec<u8>)>>),
+    /// Sent in the precise or the legacy pointer format, depending on the client.
+    Pointer(PointerState),
+    /// Only sent to clients which asked for touch data.
+    Touch(Arc<Vec<u8>>),
+    /// Only sent to clients which asked for strokes.
+    Stroke(Arc<Vec<u8>>),
+}
 
-struct ImageDelta {
-    offset: u32,
-    data: Vec<u8>,
+impl Packet {
+    /// Returns `None` if the packet isn't meant for this client.
+    fn serialize(self, options: &ClientOptions) -> Option<Vec<u8>> {
+        Some(match self {
+            Packet::Frame(packets) => find_packet(&packets, options.frame_format())?,
+            Packet::Pointer(state) if options.precise_pointer => state.serialize(),
+            Packet::Pointer(state) => state.serialize_legacy(),
+            Packet::Touch(packet) if options.touch => packet.to_vec(),
+            Packet::Touch(_) => return None,
+            Packet::Stroke(packet) if options.strokes => packet.to_vec(),
+            Packet::Stroke(_) => return None,
+        })
+    }
 }
 
-impl ImageDelta {
-    fn serialize(&self) -> Vec<u8> {
-        let mut outbound = Vec::with_capacity(self.data.len() + 4 * 2);
-        outbound.extend_from_slice(&self.offset.to_be_bytes());
-        outbound.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
-        outbound.extend_from_slice(&self.data);
+/// Compresses the deltas for the format of every connected client.
+async fn compress_for_clients(frame: &DeltaFrame) -> Arc<Vec<(FrameFormat, Vec<u8>)>> {
+    let mut packets = Vec::new();
+    for format in connected_formats() {
+        packets.push((format, frame.packet(format.codec, format.color_mode).await));
+    }
+    Arc::new(packets)
+}
 
-        outbound
+/// Encodes the keyframe for the format of every connected client.
+async fn encode_for_clients(keyframe: &Keyframe) -> Arc<Vec<(FrameFormat, Vec<u8>)>> {
+    let mut packets = Vec::new();
+    for format in connected_formats() {
+        let packet = keyframe
+            .packet(format.keyframe_format, format.codec, format.color_mode)
+            .await;
+        packets.push((format, packet));
     }
+    Arc::new(packets)
+}
+
+fn find_packet(packets: &[(FrameFormat, Vec<u8>)], format: FrameFormat) -> Option<Vec<u8>> {
+    packets
+        .iter()
+        .find(|e| e.0 == format)
+        .map(|(_, packet)| packet.clone())
 }
 
+/// Size of the most recently encoded keyframe, or 0 if none was encoded yet.
+static LAST_KEYFRAME_SIZE: AtomicUsize = AtomicUsize::new(0);
+/// Incremented every time IMAGE_DATA changes. Only modified with IMAGE_DATA locked.
+static FRAME_VERSION: AtomicU64 = AtomicU64::new(0);
+
 lazy_static! {
     static ref IMAGE_DATA: Mutex<Vec<u8>> = Mutex::new(Vec::default());
-    static ref CHANGES_BROADCASTER: Mutex<broadcast::Sender<Vec<u8>>> =
+    /// The most recent keyframe, along with the FRAME_VERSION it was taken at.
+    static ref KEYFRAME_CACHE: Mutex<Option<(u64, Arc<Keyframe>)>> = Mutex::new(None);
+    static ref CHANGES_BROADCASTER: Mutex<broadcast::Sender<Packet>> =
+        Mutex::new(broadcast::channel(100).0);
+    /// Pointer, touch and stroke packets. Kept apart from the frames, so that they can skip ahead of them.
+    static ref POINTER_BROADCASTER: Mutex<broadcast::Sender<Packet>> =
         Mutex::new(broadcast::channel(100).0);
 }
 
-async fn update_pointer_pos_forever() -> Result<()> {
-    let device_type = detect_device().unwrap();
-    let device_info = get_device_info(device_type);
-    let mut evdev_device = Device::open(device_info.digitizer_path)
-        .unwrap()
-        .into_event_stream()
-        .unwrap();
-    let mut x: i32 = 0;
-    let mut y: i32 = 0;
-    let mut d: i32 = 0;
-    let mut previous_packet = Vec::default();
-    loop {
-        let event = evdev_device.next_event().await?;
-        match event.destructure() {
-            EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _) => {
-                // Flush to the global structures
-                let values = (device_info.digitizer_data_translator)(&device_info, x, y, d);
-                let mut packet = vec![2u8];
-                packet.extend_from_slice(&values.0.to_be_bytes());
-                packet.extend_from_slice(&values.1.to_be_bytes());
-                packet.extend_from_slice(&values.2.to_be_bytes());
-                if packet == previous_packet {
-                    continue;
-                }
-                previous_packet = packet.clone();
-                let _ = CHANGES_BROADCASTER.lock().await.send(packet);
-            }
-            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_X, value) => {
-                x = value;
-            }
-            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_Y, value) => {
-                y = value;
-            }
-            EventSummary::Key(_, KeyCode::BTN_TOOL_PEN, value) => {
-                if value == 0 {
-                    d = 0;
-                }
-            }
-            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_DISTANCE, value) => {
-                d = value;
-            }
-            _ => {}
+/// Returns a keyframe of the current screen, shared with everyone else who asked since the last change.
+async fn get_current_keyframe(framebuffer_config: &FramebufferConfig) -> Arc<Keyframe> {
+    let mut cache = KEYFRAME_CACHE.lock().await;
+    let image_data = IMAGE_DATA.lock().await;
+    let version = FRAME_VERSION.load(Ordering::Relaxed);
+    if let Some((cached_version, keyframe)) = &*cache {
+        if *cached_version == version {
+            return keyframe.clone();
         }
     }
+    let (width, height) = framebuffer_config.streamed_size();
+    let keyframe = Arc::new(Keyframe::new(image_data.clone(), width, height));
+    *cache = Some((version, keyframe.clone()));
+    keyframe
 }
 
-async fn get_current_screen_as_png(framebuffer_config: &FramebufferConfig) -> Result<Vec<u8>> {
-    let mut out = vec![0u8; framebuffer_config.fb_size + 1]; // Worst-case scenario
-    let mut c = Cursor::new(&mut *out);
-    c.write_all(&[3u8]).unwrap();
-    let mut w = BufWriter::new(&mut c);
-
-    let mut encoder =
-        png::Encoder::new(&mut w, framebuffer_config.width, framebuffer_config.height);
-    encoder.set_color(png::ColorType::Rgba);
-    encoder.set_depth(png::BitDepth::Eight);
-    encoder
-        .write_header()
-        .unwrap()
-        .write_image_data(&IMAGE_DATA.lock().await)?;
-    drop(w);
-    let size = c.position() as usize;
-    Ok(out[0..size].to_vec())
-}
-
-async fn broadcast_changes_forever(mem_fd: File, config: &FramebufferConfig) -> Result<()> {
+async fn broadcast_changes_forever(
+    source: FramebufferSource,
+    config: &FramebufferConfig,
+) -> Result<()> {
+    let orientation = current_orientation();
     let mut data = vec![0u8; config.fb_size];
+    // The frame before it's rotated. Without a rotation, frames are translated straight into `temp_buffer`.
+    let mut upright_buffer = match orientation {
+        Orientation::Upright => Vec::new(),
+        _ => vec![0u8; (config.width * config.height * 4) as usize],
+    };
     let mut temp_buffer = vec![0u8; (config.width * config.height * 4) as usize];
+    // The last frame read from memory, as opposed to IMAGE_DATA, which is the last frame sent.
+    let mut previous_poll = vec![0u8; (config.width * config.height * 4) as usize];
+    let mut settler = Settler::new(
+        SETTINGS.settle_window,
+        SETTINGS.settle_max_hold,
+        SETTINGS.settle_threshold,
+        Instant::now(),
+    );
+    // Set while the framebuffer can't be read, until a whole frame is read again.
+    let mut read_failing = false;
     *IMAGE_DATA.lock().await = vec![0u8; (config.width * config.height * 4) as usize];
     loop {
         sleep(SCREEN_POLL_RATE).await;
-        if unsafe {
-            libc::lseek(
-                mem_fd.as_raw_fd(),
-                config.address as libc::off_t,
-                libc::SEEK_SET,
-            )
-        } == -1
-        {
-            bail!("Failed to read memory!");
-        }
-        let read_bytes = unsafe {
-            libc::read(
-                mem_fd.as_raw_fd(),
-                data.as_mut_ptr() as *mut libc::c_void,
-                config.fb_size,
-            )
+        // While a change is held back, the screen is polled to find out when it settles.
+        let damage = match SETTINGS.capture_mode {
+            CaptureMode::Damage if !settler.is_settling() && !read_failing => {
+                next_damage(SETTINGS.damage_fallback).await
+            }
+            _ => None,
+        };
+        let rows = match damage {
+            Some(rows) => config.visible_rows(rows),
+            None => {
+                clear_damage();
+                0..config.height
+            }
         };
-        if read_bytes != config.fb_size as isize {
-            bail!("Failed to read memory!");
+        match source.read_rows(config, &mut data, rows.clone()) {
+            Ok(()) => read_failing = false,
+            Err(e) => {
+                // Shared-memory exporters can truncate or replace their file while they restart.
+                if !read_failing {
+                    eprintln!("Can't read the framebuffer, retrying: {e:?}");
+                }
+                read_failing = true;
+                continue;
+            }
+        }
+        if orientation == Orientation::Upright {
+            config.translate_rows(&data, &mut temp_buffer, rows);
+        } else {
+            config.translate_rows(&data, &mut upright_buffer, rows);
+            orientation.rotate(
+                &upright_buffer,
+                config.width,
+                config.height,
+                &mut temp_buffer,
+            );
+        }
+
+        if temp_buffer != previous_poll {
+            settler.changed(Instant::now());
+            previous_poll.copy_from_slice(&temp_buffer);
+        } else if !settler.is_settling() {
+            // Nothing changed since the last frame was sent.
+            continue;
+        }
+
+        // While the e-ink waveform is still running, hold the change back.
+        if settler.is_holding(Instant::now()) {
+            continue;
         }
-        (config.image_data_translator)(&config, &data, &mut temp_buffer);
 
         // Encode deltas
         let mut global_ref = IMAGE_DATA.lock().await;
-        let mut deltas = Vec::new();
-
-        let mut current_delta = None;
-        let mut abandon_deltas = false;
-        for (i, (old, new)) in global_ref.iter().zip(&temp_buffer).enumerate() {
-            match (*old == *new, current_delta.is_none()) {
-                (true, true) => {}
-                (false, true) => {
-                    // There is a difference, and we're not in a delta. => Create a new delta
-                    current_delta = Some(ImageDelta {
-                        offset: i as u32,
-                        data: vec![*new],
-                    });
-                }
-                (true, false) => {
-                    // There's no difference, and we're in a delta => Finish delta.
-                    deltas.extend_from_slice(&current_delta.unwrap().serialize());
-                    current_delta = None;
-                    if deltas.len() > DELTA_PNG_THRESHOLD {
-                        // It's not worth it to send it as deltas.
-                        abandon_deltas = true;
-                        break;
-                    }
-                }
-                (false, false) => {
-                    // No changes, and delta exists => Append to delta
-                    current_delta.as_mut().unwrap().data.push(*new);
-                }
-            }
+        let deltas = encode_deltas(&global_ref, &temp_buffer);
+        // Pen-sized changes go out immediately, everything larger waits for the screen to settle.
+        if !settler.should_send(deltas.len(), Instant::now()) {
+            continue;
         }
+
+        strokes::frame_changed(changed_bytes(&deltas), temp_buffer.len());
+
         // Update the global reference.
         global_ref.copy_from_slice(&temp_buffer);
+        FRAME_VERSION.fetch_add(1, Ordering::Relaxed);
         drop(global_ref);
-
-        if abandon_deltas {
-            println!("Abandonning deltas. Sending PNG instead!");
-            if CHANGES_BROADCASTER
-                .lock()
+        // Don't keep the outdated keyframe around.
+        *KEYFRAME_CACHE.lock().await = None;
+
+        // Send whichever is smaller - the compressed deltas or a whole new keyframe.
+        // The keyframe is only encoded if the last one known wasn't already smaller than the deltas.
+        // The sizes are measured with the default codec, which also primes its cache for the clients.
+        let frame = DeltaFrame::new(deltas);
+        let delta_size = frame
+            .packet(SETTINGS.default_codec, ColorMode::Color)
+            .await
+            .len();
+        let keyframe_size = LAST_KEYFRAME_SIZE.load(Ordering::Relaxed);
+        let packet = if keyframe_size != 0 && delta_size < keyframe_size {
+            Packet::Frame(compress_for_clients(&frame).await)
+        } else {
+            let keyframe = get_current_keyframe(config).await;
+            let keyframe_size = keyframe
+                .packet(
+                    SETTINGS.default_keyframe_format,
+                    SETTINGS.default_codec,
+                    ColorMode::Color,
+                )
                 .await
-                .send(get_current_screen_as_png(config).await.unwrap())
-                .is_ok()
-            {
-                sleep(SLEEP_AFTER_PNG_TRANSMISSION).await;
+                .len();
+            LAST_KEYFRAME_SIZE.store(keyframe_size, Ordering::Relaxed);
+            if keyframe_size < delta_size {
+                println!(
+                    "Sending a keyframe instead of deltas ({} < {} bytes)",
+                    keyframe_size, delta_size
+                );
+                Packet::Frame(encode_for_clients(&keyframe).await)
+            } else {
+                Packet::Frame(compress_for_clients(&frame).await)
             }
-            continue;
-        }
-        // If there's a leftover delta, push it
-        if let Some(delta) = current_delta {
-            deltas.extend_from_slice(&delta.serialize());
-        }
-        // Compress and broadcast deltas
-        if !deltas.is_empty() {
-            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(&deltas).unwrap();
-            let final_size = deltas.len() as u32;
-            let mut deltas = encoder.finish().unwrap();
-            deltas.insert(0, 1);
-            deltas.splice(1..1, final_size.to_be_bytes());
-            let _ = CHANGES_BROADCASTER.lock().await.send(deltas);
-        }
+        };
+        let _ = CHANGES_BROADCASTER.lock().await.send(packet);
     }
 }
 
-fn run_server(fb_config: &'static FramebufferConfig) {
+fn run_server(fb_config: &'static FramebufferConfig, replier: BackendReplier<MyBackend>) {
     let page = warp::path::end().map(|| warp::reply::html(include_str!("page.html")));
     let ws_page = warp::path("ws")
         .and(warp::ws())
-        .map(move |ws: warp::ws::Ws| ws.on_upgrade(move |ws| websocket_handler(fb_config, ws)));
-    let routes = page.or(ws_page).with(warp::cors().allow_any_origin());
+        .and(warp::addr::remote())
+        .map(move |ws: warp::ws::Ws, address: Option<SocketAddr>| {
+            let replier = replier.clone();
+            let client =
+                address.map_or_else(|| "Unknown client".to_string(), |e| e.ip().to_string());
+            ws.on_upgrade(move |ws| websocket_handler(fb_config, replier, client, ws))
+        });
+    let metrics = warp::path("metrics").map(render_metrics);
+    let (width, height) = fb_config.streamed_size();
+    let strokes_svg = warp::path("strokes.svg").map(move || {
+        warp::reply::with_header(
+            render_svg(&strokes::current_page(), width, height),
+            "Content-Type",
+            "image/svg+xml",
+        )
+    });
+    let strokes_inkml = warp::path("strokes.inkml").map(move || {
+        warp::reply::with_header(
+            render_inkml(&strokes::current_page(), width, height),
+            "Content-Type",
+            "application/inkml+xml",
+        )
+    });
+    let routes = page
+        .or(ws_page)
+        .or(metrics)
+        .or(strokes_svg)
+        .or(strokes_inkml)
+        .with(warp::cors().allow_any_origin());
 
     tokio::task::spawn(warp::serve(routes).run(([0, 0, 0, 0], PORT)));
 }
 
 async fn get_config_packet(fb_config: &'static FramebufferConfig) -> Vec<u8> {
+    let (width, height) = fb_config.streamed_size();
     let mut config = vec![0u8];
-    config.extend_from_slice(&fb_config.width.to_be_bytes());
-    config.extend_from_slice(&fb_config.height.to_be_bytes());
+    config.extend_from_slice(&width.to_be_bytes());
+    config.extend_from_slice(&height.to_be_bytes());
+    serialize_capabilities(&mut config);
     config
 }
 
-async fn websocket_handler(fb_config: &'static FramebufferConfig, websocket: warp::ws::WebSocket) {
-    let (mut sender, mut _receiver) = websocket.split();
+async fn websocket_handler(
+    fb_config: &'static FramebufferConfig,
+    replier: BackendReplier<MyBackend>,
+    client: String,
+    websocket: warp::ws::WebSocket,
+) {
+    let (mut sender, mut receiver) = websocket.split();
     // Encode initial resolution-preparing packet
     if let Err(e) = {
         match sender
@@ -247,11 +358,68 @@ async fn websocket_handler(fb_config: &'static FramebufferConfig, websocket: war
         return;
     }
 
-    // Encode initial PNG data.
+    // Wait for the client to pick its options. Clients which don't know about the handshake get the defaults,
+    // and no session packet, which they wouldn't understand.
+    let (mut options, mut negotiated) = match timeout(HANDSHAKE_TIMEOUT, receiver.next()).await {
+        Ok(Some(Ok(message))) if message.is_binary() => {
+            (ClientOptions::parse_hello(message.as_bytes()), true)
+        }
+        Ok(None | Some(Err(_))) => {
+            println!("Client disconnected during the handshake");
+            return;
+        }
+        _ => (ClientOptions::default(), false),
+    };
+    if negotiated {
+        if let Err(e) = {
+            match sender
+                .send(warp::ws::Message::binary(
+                    options.serialize_session_packet(),
+                ))
+                .await
+            {
+                Ok(_) => sender.flush().await,
+                e => e,
+            }
+        } {
+            println!(
+                "Error while flushing the session packet. Disconnecting the client: {:?}",
+                e
+            );
+            return;
+        }
+    }
+    if let Some(palette) = options.color_mode.palette() {
+        if let Err(e) = {
+            match sender
+                .send(warp::ws::Message::binary(palette.serialize()))
+                .await
+            {
+                Ok(_) => sender.flush().await,
+                e => e,
+            }
+        } {
+            println!(
+                "Error while flushing the palette packet. Disconnecting the client: {:?}",
+                e
+            );
+            return;
+        }
+    }
+
+    // Frames are only compressed for the formats of the clients connected at the time.
+    let mut registration = FormatRegistration::new(options.frame_format());
+    // Subscribe before taking the keyframe, so no change made in between gets lost.
+    let mut subscriber = CHANGES_BROADCASTER.lock().await.subscribe();
+    let mut pointer_subscriber = POINTER_BROADCASTER.lock().await.subscribe();
+    // Encode the initial keyframe.
     if let Err(e) = {
         match sender
             .send(warp::ws::Message::binary(
-                get_current_screen_as_png(fb_config).await.unwrap(),
+                get_current_keyframe(fb_config)
+                    .await
+                    .packet(options.keyframe_format, options.codec, options.color_mode)
+                    .await,
             ))
             .await
         {
@@ -267,8 +435,136 @@ async fn websocket_handler(fb_config: &'static FramebufferConfig, websocket: war
     }
     println!("Initial packet sent!");
     // Now start receiving deltas
-    let mut subscriber = CHANGES_BROADCASTER.lock().await.subscribe();
-    while let Ok(delta_packet) = subscriber.recv().await {
+    let mut previous_pointer_packet = Vec::new();
+    // Packets to send before the next frame: the chunks of the frame currently being sent, or the answer to a late hello.
+    let mut pending_chunks = VecDeque::new();
+    // Waiting for the device owner to answer the client's remote control request.
+    // Not spawned, so that the request is withdrawn as soon as the client disconnects.
+    let mut approval: Option<Pin<Box<dyn Future<Output = bool> + Send>>> = None;
+    let mut remote_input: Option<RemoteInput> = None;
+    loop {
+        let delta_packet = tokio::select! {
+            // Pointer packets always go first, and can be sent between the chunks of a frame.
+            biased;
+            message = receiver.next() => match message {
+                Some(Ok(message)) if message.is_binary() => {
+                    let data = message.as_bytes();
+                    match data.first() {
+                        Some(&CLIENT_HELLO) if !negotiated => {
+                            // The hello came after the handshake timed out. Everything sent so far used the
+                            // defaults, so the client starts over from a keyframe in its own options.
+                            negotiated = true;
+                            options = ClientOptions::parse_hello(data);
+                            registration.update(options.frame_format());
+                            previous_pointer_packet.clear();
+                            subscriber = subscriber.resubscribe();
+                            pending_chunks.push_back(options.serialize_session_packet());
+                            if let Some(palette) = options.color_mode.palette() {
+                                pending_chunks.push_back(palette.serialize());
+                            }
+                            let keyframe = get_current_keyframe(fb_config)
+                                .await
+                                .packet(options.keyframe_format, options.codec, options.color_mode)
+                                .await;
+                            if options.chunked_frames {
+                                pending_chunks.extend(split_into_chunks(keyframe));
+                            } else {
+                                pending_chunks.push_back(keyframe);
+                            }
+                            continue;
+                        }
+                        Some(&CLIENT_REMOTE_INPUT_REQUEST) if !SETTINGS.remote_input => {
+                            vec![REMOTE_INPUT_PACKET, 0]
+                        }
+                        Some(&CLIENT_REMOTE_INPUT_REQUEST) => {
+                            if remote_input.is_none() && approval.is_none() {
+                                approval = Some(Box::pin(request_approval(
+                                    replier.clone(),
+                                    client.clone(),
+                                )));
+                            }
+                            continue;
+                        }
+                        Some(&CLIENT_REMOTE_INPUT_EVENT) => {
+                            if let Some(remote_input) = &mut remote_input {
+                                if let Err(e) = remote_input.handle_event(&data[1..]) {
+                                    println!("Rejected remote input from {client}: {e:?}");
+                                }
+                            }
+                            continue;
+                        }
+                        _ => continue,
+                    }
+                }
+                Some(Ok(_)) => continue,
+                None | Some(Err(_)) => break,
+            },
+            granted = async { approval.as_mut().unwrap().await }, if approval.is_some() => {
+                approval = None;
+                if granted {
+                    println!("{client} was granted the remote control");
+                    remote_input = Some(RemoteInput::default());
+                }
+                vec![REMOTE_INPUT_PACKET, u8::from(granted)]
+            }
+            pointer_packet = pointer_subscriber.recv() => match pointer_packet {
+                Ok(Packet::Pointer(state)) => {
+                    // Different precise positions can still round to the same legacy packet.
+                    let packet = Packet::Pointer(state).serialize(&options).unwrap();
+                    if packet == previous_pointer_packet {
+                        continue;
+                    }
+                    previous_pointer_packet = packet.clone();
+                    packet
+                }
+                Ok(packet) => match packet.serialize(&options) {
+                    Some(packet) => packet,
+                    None => continue,
+                },
+                // Only the latest pointer position matters, so missed ones can be skipped.
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = std::future::ready(()), if !pending_chunks.is_empty() => {
+                pending_chunks.pop_front().unwrap()
+            }
+            packet = subscriber.recv(), if pending_chunks.is_empty() => {
+                let packet = match packet {
+                    Ok(Packet::Frame(packets)) => match find_packet(
+                        &packets,
+                        options.frame_format(),
+                    ) {
+                        Some(packet) => packet,
+                        None => {
+                            // The client connected or changed its options while the frame was encoded.
+                            subscriber = subscriber.resubscribe();
+                            get_current_keyframe(fb_config)
+                                .await
+                                .packet(options.keyframe_format, options.codec, options.color_mode)
+                                .await
+                        }
+                    },
+                    Ok(packet) => match packet.serialize(&options) {
+                        Some(packet) => packet,
+                        None => continue,
+                    },
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        // Some deltas were missed - the client has to start over from a keyframe.
+                        println!("Client lagged behind. Resending the keyframe");
+                        get_current_keyframe(fb_config)
+                            .await
+                            .packet(options.keyframe_format, options.codec, options.color_mode)
+                            .await
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if options.chunked_frames {
+                    pending_chunks.extend(split_into_chunks(packet));
+                    continue;
+                }
+                packet
+            }
+        };
         if let Err(e) = {
             match sender.send(warp::ws::Message::binary(delta_packet)).await {
                 Ok(_) => sender.flush().await,
@@ -293,53 +589,125 @@ async fn real_main(
 ) -> Result<()> {
     println!("Initializing rmStream...");
     let device = match detect_device() {
-        Some(dev) => get_device_info(dev),
-        None => {
-            sender
-                .send_message(2, "The device you're using is not compatible!")
-                .unwrap();
-            println!("Device is not compatible!");
+        Ok(device) => device,
+        Err(e) => {
+            sender.send_message(2, &e.to_string()).unwrap();
+            println!("Device is not compatible: {e}");
             return Ok(());
         }
     };
+    println!("Using the device profile for {}", device.name);
 
-    let (file, framebuffer_config): (File, &'static FramebufferConfig) =
-        if let Some(framebuffer_config) = device.override_framebuffer_config {
-            let fb0_fd = OpenOptions::new()
-                .read(true)
-                .open(framebuffer_config.framebuffer_file.unwrap())?;
+    let (source, framebuffer_config, reported_orientation): (_, &'static FramebufferConfig, _) =
+        if let Some(path) = &SETTINGS.shared_memory {
+            let (source, framebuffer_config, reported_orientation) = match open_shared_memory(path)
+            {
+                Ok(framebuffer) => framebuffer,
+                Err(e) => {
+                    sender.send_message(2, &e.to_string()).unwrap();
+                    println!("Can't read the shared framebuffer: {e:?}");
+                    return Ok(());
+                }
+            };
+            println!(
+                "Mapped {} as a {}x{} {} framebuffer",
+                path.display(),
+                framebuffer_config.width,
+                framebuffer_config.height,
+                framebuffer_config.pixel_format
+            );
+
+            (
+                source,
+                &*Box::leak(Box::new(framebuffer_config)),
+                reported_orientation,
+            )
+        } else if let Some(framebuffer_file) = &device.framebuffer_file {
+            let (source, framebuffer_config) = match open_framebuffer_file(framebuffer_file) {
+                Ok(framebuffer) => framebuffer,
+                Err(e) => {
+                    sender.send_message(2, &e.to_string()).unwrap();
+                    println!("Can't read the framebuffer: {e:?}");
+                    return Ok(());
+                }
+            };
+            println!(
+                "Mapped {} as a {}x{} {} framebuffer",
+                framebuffer_file.path,
+                framebuffer_config.width,
+                framebuffer_config.height,
+                framebuffer_config.pixel_format
+            );
 
-            (fb0_fd, framebuffer_config)
+            (source, &*Box::leak(Box::new(framebuffer_config)), None)
         } else {
             eprintln!("Opening xochitl's memory");
             let mem_fd = OpenOptions::new()
                 .read(true)
                 .open(format!("/proc/{}/mem", pid))?;
-            if let Ok(framebuffer_spy_config) =
-                FramebufferSpyConfig::parse(&framebuffer_spy_config_string)
-            {
-                eprintln!(
-                    "Framebuffer config is {framebuffer_spy_config:?} according to framebuffer-spy"
-                );
+            let framebuffer_spy_config =
+                match FramebufferSpyConfig::parse(&framebuffer_spy_config_string) {
+                    Ok(framebuffer_spy_config) => framebuffer_spy_config,
+                    Err(FramebufferSpyConfigParsingError::Missing) => {
+                        sender
+                            .send_message(2, "No framebuffer-spy installed")
+                            .unwrap();
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        sender
+                            .send_message(
+                                2,
+                                &format!("framebuffer-spy returned an invalid config: {e}"),
+                            )
+                            .unwrap();
+                        println!(
+                            "Invalid framebuffer-spy config {framebuffer_spy_config_string:?}: {e}"
+                        );
+                        return Ok(());
+                    }
+                };
+            eprintln!(
+                "Framebuffer config is {framebuffer_spy_config:?} according to framebuffer-spy"
+            );
+            let reported_orientation = framebuffer_spy_config.rotation;
+            let framebuffer_config = match FramebufferConfig::try_from(framebuffer_spy_config) {
+                Ok(framebuffer_config) => framebuffer_config,
+                Err(e) => {
+                    sender.send_message(2, &e.to_string()).unwrap();
+                    println!("Framebuffer is not supported: {e}");
+                    return Ok(());
+                }
+            };
 
-                (
-                    mem_fd,
-                    Box::leak(Box::new(FramebufferConfig::from(framebuffer_spy_config))),
-                )
-            } else {
-                sender
-                    .send_message(2, "No framebuffer-spy installed")
-                    .unwrap();
-                return Ok(());
-            }
+            (
+                FramebufferSource::Memory(mem_fd),
+                &*Box::leak(Box::new(framebuffer_config)),
+                reported_orientation,
+            )
         };
 
-    tokio::spawn(broadcast_changes_forever(file, framebuffer_config));
-    tokio::spawn(update_pointer_pos_forever());
+    if SETTINGS.capture_mode == CaptureMode::Damage {
+        println!("Experimental damage capture enabled. Polling the framebuffer until framebuffer-spy sends its first damage report");
+    }
+    let orientation = detect_orientation(reported_orientation);
+    println!("Streaming with a rotation of {orientation} degrees");
+    let _ = PAGE_SIZE.set(framebuffer_config.streamed_size());
+    tokio::spawn(broadcast_changes_forever(source, framebuffer_config));
+    tokio::spawn(async {
+        if let Err(e) = update_pointer_pos_forever().await {
+            eprintln!("Pointer tracking stopped: {e:?}");
+        }
+    });
+    tokio::spawn(async {
+        if let Err(e) = update_touch_forever().await {
+            eprintln!("Touch tracking stopped: {e:?}");
+        }
+    });
 
     sender.backend.lock().await.ready = true;
     sender.send_message(1, "ready").unwrap();
-    run_server(framebuffer_config);
+    run_server(framebuffer_config, sender);
     Ok(())
 }
 
@@ -372,6 +740,13 @@ impl AppLoadBackend for MyBackend {
                     .send_message(0, &format!("{},{}", self.ready, self.ip_addrs.join(",")))
                     .unwrap();
             }
+            102 => match CalibrationInput::from_name(&message.contents) {
+                Some(input) => start_calibration(input, functionality.clone()),
+                None => eprintln!("Unknown calibration input: {}", message.contents),
+            },
+            103 => cancel_calibration(),
+            104 => answer_approval(&message.contents),
+            105 => report_damage(&message.contents),
             m => {
                 eprintln!("Unhandled message type: {}", m);
             }
@@ -405,5 +780,13 @@ async fn main() {
     };
 
     let mut appload = AppLoad::new(backend).unwrap();
-    appload.run().await.unwrap();
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        result = appload.run() => result.unwrap(),
+        _ = terminate.recv() => println!("Terminated"),
+    }
+    // The session is over - keep the notes taken during it.
+    if let Err(e) = export_session() {
+        eprintln!("Failed to export the strokes: {e:?}");
+    }
 }
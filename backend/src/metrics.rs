@@ -0,0 +1,76 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::codec::Codec;
+
+struct CompressionMetrics {
+    runs: AtomicU64,
+    input_bytes: AtomicU64,
+    output_bytes: AtomicU64,
+    time_micros: AtomicU64,
+}
+
+impl CompressionMetrics {
+    const fn new() -> Self {
+        Self {
+            runs: AtomicU64::new(0),
+            input_bytes: AtomicU64::new(0),
+            output_bytes: AtomicU64::new(0),
+            time_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+static COMPRESSION_METRICS: [CompressionMetrics; Codec::ALL.len()] =
+    [const { CompressionMetrics::new() }; Codec::ALL.len()];
+
+pub fn record_compression(codec: Codec, input_bytes: usize, output_bytes: usize, time: Duration) {
+    let metrics = &COMPRESSION_METRICS[codec.id() as usize];
+    metrics.runs.fetch_add(1, Ordering::Relaxed);
+    metrics
+        .input_bytes
+        .fetch_add(input_bytes as u64, Ordering::Relaxed);
+    metrics
+        .output_bytes
+        .fetch_add(output_bytes as u64, Ordering::Relaxed);
+    metrics
+        .time_micros
+        .fetch_add(time.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Renders all the metrics in the Prometheus text format.
+pub fn render_metrics() -> String {
+    let mut out = String::new();
+    for codec in Codec::ALL {
+        let metrics = &COMPRESSION_METRICS[codec.id() as usize];
+        let runs = metrics.runs.load(Ordering::Relaxed);
+        let input_bytes = metrics.input_bytes.load(Ordering::Relaxed);
+        let output_bytes = metrics.output_bytes.load(Ordering::Relaxed);
+        let time_micros = metrics.time_micros.load(Ordering::Relaxed);
+        let name = codec.name();
+        let ratio = if output_bytes == 0 {
+            0.0
+        } else {
+            input_bytes as f64 / output_bytes as f64
+        };
+        let _ = writeln!(out, "rmstream_compression_runs{{codec=\"{name}\"}} {runs}");
+        let _ = writeln!(
+            out,
+            "rmstream_compression_input_bytes{{codec=\"{name}\"}} {input_bytes}"
+        );
+        let _ = writeln!(
+            out,
+            "rmstream_compression_output_bytes{{codec=\"{name}\"}} {output_bytes}"
+        );
+        let _ = writeln!(
+            out,
+            "rmstream_compression_time_micros{{codec=\"{name}\"}} {time_micros}"
+        );
+        let _ = writeln!(
+            out,
+            "rmstream_compression_ratio{{codec=\"{name}\"}} {ratio:.3}"
+        );
+    }
+    out
+}
@@ -0,0 +1,151 @@
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::calibration::Affine;
+use crate::settings::SETTINGS;
+
+/// How far the frames are rotated clockwise before they're streamed, so that they're upright for the viewers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Orientation {
+    #[default]
+    Upright,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+static ORIENTATION: OnceLock<Orientation> = OnceLock::new();
+
+/// Picks the orientation once the framebuffer is known. The `orientation` setting takes precedence over
+/// the rotation reported by framebuffer-spy. Without either, frames are streamed as they are, and viewers
+/// can still rotate them on their side.
+pub fn detect_orientation(reported: Option<Orientation>) -> Orientation {
+    let orientation = SETTINGS.orientation.or(reported).unwrap_or_default();
+    *ORIENTATION.get_or_init(|| orientation)
+}
+
+/// The orientation picked by `detect_orientation`, or the configured one if the framebuffer isn't known yet.
+pub fn current_orientation() -> Orientation {
+    ORIENTATION
+        .get()
+        .copied()
+        .unwrap_or_else(|| SETTINGS.orientation.unwrap_or_default())
+}
+
+impl Orientation {
+    pub fn from_degrees(degrees: u32) -> Option<Self> {
+        match degrees {
+            0 => Some(Orientation::Upright),
+            90 => Some(Orientation::Clockwise90),
+            180 => Some(Orientation::Clockwise180),
+            270 => Some(Orientation::Clockwise270),
+            _ => None,
+        }
+    }
+
+    pub fn degrees(self) -> u16 {
+        match self {
+            Orientation::Upright => 0,
+            Orientation::Clockwise90 => 90,
+            Orientation::Clockwise180 => 180,
+            Orientation::Clockwise270 => 270,
+        }
+    }
+
+    /// Maps a position on the screen (0.0 - 1.0) onto the rotated frames.
+    pub fn transform(self) -> Affine {
+        match self {
+            Orientation::Upright => Affine::IDENTITY,
+            Orientation::Clockwise90 => Affine([0.0, -1.0, 1.0, 1.0, 0.0, 0.0]),
+            Orientation::Clockwise180 => Affine([-1.0, 0.0, 1.0, 0.0, -1.0, 1.0]),
+            Orientation::Clockwise270 => Affine([0.0, 1.0, 0.0, -1.0, 0.0, 1.0]),
+        }
+    }
+
+    /// The size of a `width` x `height` frame once it's rotated.
+    pub fn rotated_size(self, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            Orientation::Upright | Orientation::Clockwise180 => (width, height),
+            Orientation::Clockwise90 | Orientation::Clockwise270 => (height, width),
+        }
+    }
+
+    /// Rotates a `width` x `height` RGBA8888 frame into `out_data`.
+    pub fn rotate(self, in_data: &[u8], width: u32, height: u32, out_data: &mut [u8]) {
+        if self == Orientation::Upright {
+            out_data.copy_from_slice(in_data);
+            return;
+        }
+        let out_width = self.rotated_size(width, height).0 as usize;
+        let (width, height) = (width as usize, height as usize);
+        for (i, out) in out_data.chunks_exact_mut(4).enumerate() {
+            let (x, y) = (i % out_width, i / out_width);
+            let (source_x, source_y) = match self {
+                Orientation::Upright => (x, y),
+                Orientation::Clockwise90 => (y, height - 1 - x),
+                Orientation::Clockwise180 => (width - 1 - x, height - 1 - y),
+                Orientation::Clockwise270 => (width - 1 - y, x),
+            };
+            let source = 4 * (source_y * width + source_x);
+            out.copy_from_slice(&in_data[source..source + 4]);
+        }
+    }
+}
+
+impl FromStr for Orientation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().ok().and_then(Self::from_degrees).ok_or(())
+    }
+}
+
+impl Display for Orientation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.degrees())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3x2 frame with a distinct gray level for every pixel.
+    const FRAME: [u8; 24] = [
+        0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, //
+        3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5,
+    ];
+
+    fn rotate(orientation: Orientation) -> Vec<u8> {
+        let mut out = vec![0u8; FRAME.len()];
+        orientation.rotate(&FRAME, 3, 2, &mut out);
+        out.chunks_exact(4).map(|e| e[0]).collect()
+    }
+
+    #[test]
+    fn rotates_frames() {
+        assert_eq!(rotate(Orientation::Upright), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(rotate(Orientation::Clockwise90), [3, 0, 4, 1, 5, 2]);
+        assert_eq!(rotate(Orientation::Clockwise180), [5, 4, 3, 2, 1, 0]);
+        assert_eq!(rotate(Orientation::Clockwise270), [2, 5, 1, 4, 0, 3]);
+    }
+
+    #[test]
+    fn positions_follow_the_frames() {
+        // The top left corner of the screen.
+        let corners = [
+            (Orientation::Upright, (0.0, 0.0)),
+            (Orientation::Clockwise90, (1.0, 0.0)),
+            (Orientation::Clockwise180, (1.0, 1.0)),
+            (Orientation::Clockwise270, (0.0, 1.0)),
+        ];
+        for (orientation, expected) in corners {
+            assert_eq!(
+                orientation.transform().apply(0.0, 0.0),
+                expected,
+                "{orientation}"
+            );
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt::Display;
+
+use serde::Deserialize;
+
+/// How the pixels are laid out in the framebuffer. Every format is converted to RGBA8888 for the clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFormat {
+    /// 16-bit little-endian, 5 bits of red, 6 of green and 5 of blue.
+    Rgb565,
+    /// Blue, green, red, then alpha, a byte each.
+    Bgra8888,
+    /// Red, green, blue, then alpha, a byte each.
+    Rgba8888,
+    /// 32-bit little-endian 0xXXRRGGBB, so blue, green, red, then an unused byte.
+    Xrgb8888,
+    /// 8-bit grayscale.
+    Y8,
+    /// 4-bit grayscale, two pixels per byte. The first pixel is in the high nibble.
+    Y4,
+}
+
+/// Converts framebuffer data into RGBA8888.
+type Translator = fn(&[u8], &mut [u8]);
+
+impl PixelFormat {
+    pub const ALL: [PixelFormat; 6] = [
+        PixelFormat::Rgb565,
+        PixelFormat::Bgra8888,
+        PixelFormat::Rgba8888,
+        PixelFormat::Xrgb8888,
+        PixelFormat::Y8,
+        PixelFormat::Y4,
+    ];
+
+    /// The pixel format type ids framebuffer-spy reports. Only these two are defined by framebuffer-spy,
+    /// other formats can only be picked by name.
+    pub fn spy_type(self) -> Option<u32> {
+        match self {
+            PixelFormat::Rgb565 => Some(1),
+            PixelFormat::Bgra8888 => Some(2),
+            PixelFormat::Rgba8888 | PixelFormat::Xrgb8888 | PixelFormat::Y8 | PixelFormat::Y4 => {
+                None
+            }
+        }
+    }
+
+    pub fn from_spy_type(r#type: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.spy_type() == Some(r#type))
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PixelFormat::Rgb565 => "rgb565",
+            PixelFormat::Bgra8888 => "bgra8888",
+            PixelFormat::Rgba8888 => "rgba8888",
+            PixelFormat::Xrgb8888 => "xrgb8888",
+            PixelFormat::Y8 => "y8",
+            PixelFormat::Y4 => "y4",
+        }
+    }
+
+    pub fn bits_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => 16,
+            PixelFormat::Bgra8888 | PixelFormat::Rgba8888 | PixelFormat::Xrgb8888 => 32,
+            PixelFormat::Y8 => 8,
+            PixelFormat::Y4 => 4,
+        }
+    }
+
+    /// Converts `in_data` into RGBA8888 pixels, as many as fit into `out_data`.
+    /// Pixels missing from `in_data` are left untouched.
+    pub fn translate(self, in_data: &[u8], out_data: &mut [u8]) {
+        let translator: Translator = match self {
+            PixelFormat::Rgb565 => translate_rgb565,
+            PixelFormat::Bgra8888 => translate_bgra8888,
+            PixelFormat::Rgba8888 => translate_rgba8888,
+            PixelFormat::Xrgb8888 => translate_xrgb8888,
+            PixelFormat::Y8 => translate_y8,
+            PixelFormat::Y4 => translate_y4,
+        };
+        translator(in_data, out_data)
+    }
+
+    /// Converts `width` pixels out of every `stride` bytes of `in_data` into tightly packed RGBA8888 rows,
+    /// dropping the padding at the end of each row.
+    pub fn translate_rows(self, in_data: &[u8], stride: usize, width: u32, out_data: &mut [u8]) {
+        let out_stride = width as usize * 4;
+        for (row, out) in in_data
+            .chunks(stride)
+            .zip(out_data.chunks_exact_mut(out_stride))
+        {
+            self.translate(row, out);
+        }
+    }
+}
+
+impl Display for PixelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+fn translate_rgb565(in_data: &[u8], out_data: &mut [u8]) {
+    for (pixel, out) in in_data.chunks_exact(2).zip(out_data.chunks_exact_mut(4)) {
+        let total = u16::from_le_bytes([pixel[0], pixel[1]]);
+        let r5 = (total >> 11) & 0x1F;
+        let g6 = (total >> 5) & 0x3F;
+        let b5 = total & 0x1F;
+        out[0] = ((r5 * 255) / 31) as u8;
+        out[1] = ((g6 * 255) / 63) as u8;
+        out[2] = ((b5 * 255) / 31) as u8;
+        out[3] = 0xFF;
+    }
+}
+
+fn translate_bgra8888(in_data: &[u8], out_data: &mut [u8]) {
+    for (pixel, out) in in_data.chunks_exact(4).zip(out_data.chunks_exact_mut(4)) {
+        out.copy_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+    }
+}
+
+fn translate_rgba8888(in_data: &[u8], out_data: &mut [u8]) {
+    for (pixel, out) in in_data.chunks_exact(4).zip(out_data.chunks_exact_mut(4)) {
+        out.copy_from_slice(pixel);
+    }
+}
+
+fn translate_xrgb8888(in_data: &[u8], out_data: &mut [u8]) {
+    for (pixel, out) in in_data.chunks_exact(4).zip(out_data.chunks_exact_mut(4)) {
+        out.copy_from_slice(&[pixel[2], pixel[1], pixel[0], 0xFF]);
+    }
+}
+
+fn translate_y8(in_data: &[u8], out_data: &mut [u8]) {
+    for (gray, out) in in_data.iter().zip(out_data.chunks_exact_mut(4)) {
+        out.copy_from_slice(&[*gray, *gray, *gray, 0xFF]);
+    }
+}
+
+fn translate_y4(in_data: &[u8], out_data: &mut [u8]) {
+    let grays = in_data
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0F])
+        .map(|gray| gray * 17);
+    for (gray, out) in grays.zip(out_data.chunks_exact_mut(4)) {
+        out.copy_from_slice(&[gray, gray, gray, 0xFF]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translate(format: PixelFormat, in_data: &[u8], pixels: usize) -> Vec<u8> {
+        let mut out = vec![0u8; pixels * 4];
+        format.translate(in_data, &mut out);
+        out
+    }
+
+    #[test]
+    fn rgb565() {
+        // Pure red, then pure blue.
+        assert_eq!(
+            translate(PixelFormat::Rgb565, &[0x00, 0xF8, 0x1F, 0x00], 2),
+            [255, 0, 0, 255, 0, 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn bgra8888() {
+        assert_eq!(
+            translate(PixelFormat::Bgra8888, &[1, 2, 3, 4], 1),
+            [3, 2, 1, 4]
+        );
+    }
+
+    #[test]
+    fn rgba8888() {
+        assert_eq!(
+            translate(PixelFormat::Rgba8888, &[1, 2, 3, 4], 1),
+            [1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn xrgb8888() {
+        assert_eq!(
+            translate(PixelFormat::Xrgb8888, &[1, 2, 3, 0], 1),
+            [3, 2, 1, 255]
+        );
+    }
+
+    #[test]
+    fn y8() {
+        assert_eq!(
+            translate(PixelFormat::Y8, &[0x00, 0x80], 2),
+            [0, 0, 0, 255, 0x80, 0x80, 0x80, 255]
+        );
+    }
+
+    #[test]
+    fn y4() {
+        assert_eq!(
+            translate(PixelFormat::Y4, &[0xF0], 2),
+            [255, 255, 255, 255, 0, 0, 0, 255]
+        );
+    }
+
+    #[test]
+    fn short_input_does_not_panic() {
+        for format in PixelFormat::ALL {
+            let out = translate(format, &[0xFF; 3], 4);
+            assert_eq!(out.len(), 16, "{format}");
+        }
+    }
+
+    #[test]
+    fn padding_is_cropped() {
+        // Two rows of two visible pixels, each padded to 4 pixels.
+        let in_data = [1, 2, 0xAA, 0xAA, 3, 4, 0xAA, 0xAA];
+        let mut out = vec![0u8; 4 * 4];
+        PixelFormat::Y8.translate_rows(&in_data, 4, 2, &mut out);
+        assert_eq!(
+            out,
+            [1, 1, 1, 255, 2, 2, 2, 255, 3, 3, 3, 255, 4, 4, 4, 255]
+        );
+    }
+
+    #[test]
+    fn spy_types_round_trip() {
+        for format in PixelFormat::ALL {
+            if let Some(r#type) = format.spy_type() {
+                assert_eq!(PixelFormat::from_spy_type(r#type), Some(format));
+            }
+        }
+        assert_eq!(PixelFormat::from_spy_type(1), Some(PixelFormat::Rgb565));
+        assert_eq!(PixelFormat::from_spy_type(2), Some(PixelFormat::Bgra8888));
+        assert_eq!(PixelFormat::from_spy_type(0), None);
+        assert_eq!(PixelFormat::from_spy_type(3), None);
+    }
+}
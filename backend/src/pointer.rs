@@ -0,0 +1,299 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use evdev::{AbsoluteAxisCode, Device, EventSummary, KeyCode, SynchronizationCode};
+
+use crate::calibration::{get_transform, record_calibration_point, CalibrationInput};
+use crate::devices::{detect_device, DigitizerData};
+use crate::orientation::current_orientation;
+use crate::strokes::StrokeTracker;
+use crate::{Packet, POINTER_BROADCASTER};
+
+/// Position as integer percentages, kept for clients which don't negotiate the precise pointer.
+pub const LEGACY_POINTER_PACKET: u8 = 2;
+/// Position as big-endian f32s normalized to the 0.0 - 1.0 range, the `PenState` id,
+/// the pressure (0.0 - 1.0) and the tilt along both screen axes (-1.0 - 1.0) as f32s,
+/// then the `PenTool` id and the hover distance (0.0 - 1.0) as an f32.
+pub const POINTER_PACKET: u8 = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PenState {
+    OutOfRange,
+    Hovering,
+    Touching,
+    /// Touching the screen with the eraser end.
+    Erasing,
+}
+
+impl PenState {
+    pub fn id(self) -> u8 {
+        match self {
+            PenState::OutOfRange => 0,
+            PenState::Hovering => 1,
+            PenState::Touching => 2,
+            PenState::Erasing => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PenTool {
+    Pen,
+    Eraser,
+}
+
+/// The pen's state, as tracked from the digitizer's key events.
+#[derive(Default)]
+struct PenKeys {
+    tool_pen: bool,
+    tool_rubber: bool,
+    touch: bool,
+}
+
+impl PenKeys {
+    /// Applies a key event. Keys which don't concern the pen are ignored.
+    fn update(&mut self, key: KeyCode, pressed: bool) {
+        match key {
+            KeyCode::BTN_TOOL_PEN => self.tool_pen = pressed,
+            KeyCode::BTN_TOOL_RUBBER => self.tool_rubber = pressed,
+            KeyCode::BTN_TOUCH => self.touch = pressed,
+            _ => {}
+        }
+    }
+
+    fn tool(&self) -> PenTool {
+        if self.tool_rubber {
+            PenTool::Eraser
+        } else {
+            PenTool::Pen
+        }
+    }
+
+    fn state(&self) -> PenState {
+        match (self.tool_pen || self.tool_rubber, self.touch, self.tool()) {
+            (false, _, _) => PenState::OutOfRange,
+            (true, false, _) => PenState::Hovering,
+            (true, true, PenTool::Pen) => PenState::Touching,
+            (true, true, PenTool::Eraser) => PenState::Erasing,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PointerState {
+    /// Horizontal position on the screen, from 0.0 to 1.0.
+    pub x: f32,
+    /// Vertical position on the screen, from 0.0 to 1.0.
+    pub y: f32,
+    pub state: PenState,
+    pub tool: PenTool,
+    /// From 0.0 to 1.0. Only meaningful while hovering.
+    pub distance: f32,
+    /// From 0.0 to 1.0.
+    pub pressure: f32,
+    /// Tilt along the screen's horizontal axis, from -1.0 to 1.0.
+    pub tilt_x: f32,
+    /// Tilt along the screen's vertical axis, from -1.0 to 1.0.
+    pub tilt_y: f32,
+}
+
+impl PointerState {
+    pub fn serialize_legacy(&self) -> Vec<u8> {
+        let mut packet = vec![LEGACY_POINTER_PACKET];
+        packet.extend_from_slice(&((self.x * 100.0) as i32).to_be_bytes());
+        packet.extend_from_slice(&((self.y * 100.0) as i32).to_be_bytes());
+        packet.extend_from_slice(&i32::from(self.state != PenState::OutOfRange).to_be_bytes());
+        packet
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut packet = vec![POINTER_PACKET];
+        packet.extend_from_slice(&self.x.to_be_bytes());
+        packet.extend_from_slice(&self.y.to_be_bytes());
+        packet.push(self.state.id());
+        packet.extend_from_slice(&self.pressure.to_be_bytes());
+        packet.extend_from_slice(&self.tilt_x.to_be_bytes());
+        packet.extend_from_slice(&self.tilt_y.to_be_bytes());
+        packet.push(match self.tool {
+            PenTool::Pen => 0,
+            PenTool::Eraser => 1,
+        });
+        packet.extend_from_slice(&self.distance.to_be_bytes());
+        packet
+    }
+}
+
+pub async fn update_pointer_pos_forever() -> Result<()> {
+    let device_info = detect_device()?;
+    let digitizer = device_info.find_digitizer()?;
+    println!("Using digitizer {digitizer:?}");
+    let mut evdev_device = Device::open(&digitizer.path)?.into_event_stream()?;
+    let mut data = DigitizerData::default();
+    let mut keys = PenKeys::default();
+    let mut previous_state: Option<PointerState> = None;
+    let mut strokes = StrokeTracker::default();
+    loop {
+        let event = evdev_device.next_event().await?;
+        match event.destructure() {
+            EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _) => {
+                // Flush to the global structures
+                let transform =
+                    get_transform(CalibrationInput::Pen, device_info.digitizer_transform);
+                let orientation = current_orientation().transform();
+                let pen = digitizer.translate(&transform, &data);
+                let (x, y) = orientation.apply(pen.x, pen.y);
+                let (tilt_x, tilt_y) = orientation.apply_linear(pen.tilt_x, pen.tilt_y);
+                let pen_state = keys.state();
+                if pen_state == PenState::Touching
+                    && previous_state.map(|e| e.state) != Some(PenState::Touching)
+                {
+                    let (x, y) = digitizer.normalize_position(&data);
+                    record_calibration_point(CalibrationInput::Pen, x, y);
+                }
+                let state = PointerState {
+                    x: x as f32,
+                    y: y as f32,
+                    state: pen_state,
+                    tool: keys.tool(),
+                    distance: if pen_state == PenState::Hovering {
+                        pen.distance.clamp(0.0, 1.0) as f32
+                    } else {
+                        0.0
+                    },
+                    pressure: if matches!(pen_state, PenState::Touching | PenState::Erasing) {
+                        pen.pressure.clamp(0.0, 1.0) as f32
+                    } else {
+                        0.0
+                    },
+                    tilt_x: tilt_x.clamp(-1.0, 1.0) as f32,
+                    tilt_y: tilt_y.clamp(-1.0, 1.0) as f32,
+                };
+                if previous_state == Some(state) {
+                    continue;
+                }
+                previous_state = Some(state);
+                let broadcaster = POINTER_BROADCASTER.lock().await;
+                if let Some(packet) = strokes.update(&state) {
+                    let _ = broadcaster.send(Packet::Stroke(Arc::new(packet)));
+                }
+                let _ = broadcaster.send(Packet::Pointer(state));
+            }
+            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_X, value) => {
+                data.x = value;
+            }
+            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_Y, value) => {
+                data.y = value;
+            }
+            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_DISTANCE, value) => {
+                data.distance = value;
+            }
+            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_PRESSURE, value) => {
+                data.pressure = value;
+            }
+            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_TILT_X, value) => {
+                data.tilt_x = value;
+            }
+            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_TILT_Y, value) => {
+                data.tilt_y = value;
+            }
+            EventSummary::Key(_, key, value) => {
+                keys.update(key, value != 0);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn states(events: &[(KeyCode, bool)]) -> Vec<(PenState, PenTool)> {
+        let mut keys = PenKeys::default();
+        events
+            .iter()
+            .map(|&(key, pressed)| {
+                keys.update(key, pressed);
+                (keys.state(), keys.tool())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn follows_the_pen_through_a_stroke() {
+        use PenState::*;
+        assert_eq!(PenKeys::default().state(), OutOfRange);
+        assert_eq!(
+            states(&[
+                (KeyCode::BTN_TOOL_PEN, true),
+                (KeyCode::BTN_TOUCH, true),
+                (KeyCode::BTN_TOUCH, false),
+                (KeyCode::BTN_TOOL_PEN, false),
+            ]),
+            [
+                (Hovering, PenTool::Pen),
+                (Touching, PenTool::Pen),
+                (Hovering, PenTool::Pen),
+                (OutOfRange, PenTool::Pen),
+            ]
+        );
+    }
+
+    #[test]
+    fn tells_the_eraser_apart() {
+        use PenState::*;
+        assert_eq!(
+            states(&[
+                (KeyCode::BTN_TOOL_RUBBER, true),
+                (KeyCode::BTN_TOUCH, true),
+                (KeyCode::BTN_TOUCH, false),
+                (KeyCode::BTN_TOOL_RUBBER, false),
+            ]),
+            [
+                (Hovering, PenTool::Eraser),
+                (Erasing, PenTool::Eraser),
+                (Hovering, PenTool::Eraser),
+                (OutOfRange, PenTool::Pen),
+            ]
+        );
+    }
+
+    #[test]
+    fn touch_without_a_tool_is_out_of_range() {
+        assert_eq!(
+            states(&[(KeyCode::BTN_TOUCH, true), (KeyCode::BTN_STYLUS, true)]),
+            [
+                (PenState::OutOfRange, PenTool::Pen),
+                (PenState::OutOfRange, PenTool::Pen),
+            ]
+        );
+    }
+
+    #[test]
+    fn serializes_the_precise_pointer() {
+        let state = PointerState {
+            x: 0.25,
+            y: 0.5,
+            state: PenState::Erasing,
+            tool: PenTool::Eraser,
+            distance: 0.0,
+            pressure: 1.0,
+            tilt_x: -1.0,
+            tilt_y: 0.0,
+        };
+        let packet = state.serialize();
+        assert_eq!(packet.len(), 27);
+        assert_eq!((packet[0], packet[9], packet[22]), (POINTER_PACKET, 3, 1));
+        assert_eq!(packet[1..5], 0.25f32.to_be_bytes());
+        assert_eq!(
+            state.serialize_legacy(),
+            [
+                [LEGACY_POINTER_PACKET].as_slice(),
+                &[0, 0, 0, 25],
+                &[0, 0, 0, 50],
+                &[0, 0, 0, 1]
+            ]
+            .concat()
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::calibration::Affine;
+use crate::devices::{Device, DigitizerProfile, FramebufferFile};
+use crate::pixel_format::PixelFormat;
+use crate::settings::CONFIG_DIRECTORY;
+
+const BUILTIN_PROFILES: &str = include_str!("devices.toml");
+const USER_PROFILES_FILE: &str = "/home/root/.config/rmstream/devices.toml";
+const MACHINE_FILE: &str = "/sys/devices/soc0/machine";
+
+#[derive(Deserialize)]
+struct ProfileFile {
+    #[serde(default, rename = "device")]
+    devices: Vec<Profile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    name: String,
+    #[serde(rename = "match")]
+    rules: MatchRules,
+    #[serde(default = "identity")]
+    digitizer_transform: Affine,
+    #[serde(default)]
+    digitizer: DigitizerProfile,
+    #[serde(default = "identity")]
+    touch_transform: Affine,
+    touchscreen_path: Option<String>,
+    framebuffer: Option<FramebufferProfile>,
+    palette: Option<Vec<[u8; 3]>>,
+}
+
+fn identity() -> Affine {
+    Affine::IDENTITY
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatchRules {
+    /// Any of these has to be found in the machine name.
+    #[serde(default)]
+    machine: Vec<String>,
+    /// All of these files have to contain their text.
+    #[serde(default)]
+    sysfs: Vec<SysfsRule>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SysfsRule {
+    path: String,
+    contains: String,
+}
+
+/// A framebuffer read directly from a file, instead of from xochitl's memory.
+/// Framebuffer devices report their own geometry, so only plain files need it.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FramebufferProfile {
+    file: String,
+    address: Option<usize>,
+    width: Option<u32>,
+    height: Option<u32>,
+    /// The length of a row in bytes, if the rows are padded past `width`.
+    stride: Option<usize>,
+    pixel_format: Option<PixelFormat>,
+}
+
+impl MatchRules {
+    fn matches(&self, machine: &str) -> bool {
+        if self.machine.is_empty() && self.sysfs.is_empty() {
+            // A profile without rules would match everything.
+            return false;
+        }
+        (self.machine.is_empty()
+            || self
+                .machine
+                .iter()
+                .any(|e| machine.contains(&e.to_lowercase())))
+            && self.sysfs.iter().all(|rule| {
+                std::fs::read_to_string(&rule.path)
+                    .is_ok_and(|contents| contents.contains(&rule.contains))
+            })
+    }
+}
+
+impl From<Profile> for Device {
+    fn from(profile: Profile) -> Self {
+        Device {
+            name: profile.name,
+            digitizer_transform: profile.digitizer_transform,
+            digitizer_profile: profile.digitizer,
+            touch_transform: profile.touch_transform,
+            touchscreen_path: profile.touchscreen_path,
+            framebuffer_file: profile.framebuffer.map(|e| FramebufferFile {
+                path: e.file,
+                address: e.address,
+                width: e.width,
+                height: e.height,
+                stride: e.stride,
+                pixel_format: e.pixel_format,
+            }),
+            palette: profile.palette,
+        }
+    }
+}
+
+fn load_profiles() -> Vec<Profile> {
+    let mut profiles = Vec::new();
+    match std::fs::read_to_string(USER_PROFILES_FILE) {
+        Ok(contents) => match toml::from_str::<ProfileFile>(&contents) {
+            Ok(file) => profiles.extend(file.devices),
+            Err(e) => eprintln!("Ignoring {USER_PROFILES_FILE}: {e}"),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Couldn't read {USER_PROFILES_FILE}: {e}"),
+    }
+    profiles.extend(
+        toml::from_str::<ProfileFile>(BUILTIN_PROFILES)
+            .expect("The built-in device profiles are invalid")
+            .devices,
+    );
+    profiles
+}
+
+/// Picks the first profile matching the hardware we're running on.
+pub fn detect_profile() -> Result<Device> {
+    let machine = std::fs::read_to_string(MACHINE_FILE)
+        .with_context(|| format!("Couldn't read {MACHINE_FILE}"))?;
+    let machine = machine.trim().to_lowercase();
+    match load_profiles()
+        .into_iter()
+        .find(|profile| profile.rules.matches(&machine))
+    {
+        Some(profile) => Ok(profile.into()),
+        None => bail!(
+            "Unsupported device \"{machine}\". A profile for it can be added to {CONFIG_DIRECTORY}/devices.toml"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_profiles_match_their_devices() {
+        let profiles = toml::from_str::<ProfileFile>(BUILTIN_PROFILES)
+            .unwrap()
+            .devices;
+        let expected = [
+            ("reMarkable 1.0", "reMarkable 1"),
+            ("reMarkable Prototype 1", "reMarkable 1"),
+            ("reMarkable 2.0", "reMarkable 2"),
+            ("reMarkable Ferrari", "reMarkable Paper Pro"),
+            ("reMarkable Chiappa", "reMarkable Paper Pro Move"),
+            ("reMarkable Tatsu", "reMarkable Paper Pure"),
+        ];
+        for (machine, name) in expected {
+            let machine = machine.to_lowercase();
+            let profile = profiles
+                .iter()
+                .find(|profile| profile.rules.matches(&machine));
+            assert_eq!(profile.map(|e| e.name.as_str()), Some(name), "{machine}");
+        }
+        assert!(!profiles.iter().any(|e| e.rules.matches("some other board")));
+        // Every built-in profile keeps the digitizer size known for its device, in case it isn't reported.
+        assert!(profiles.iter().all(|e| e.digitizer.x.is_some()));
+    }
+}
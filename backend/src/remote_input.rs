@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use appload_client::BackendReplier;
+use evdev::uinput::VirtualDevice;
+use evdev::{
+    AbsInfo, AbsoluteAxisCode, AbsoluteAxisEvent, AttributeSet, InputEvent, KeyCode, KeyEvent,
+    UinputAbsSetup,
+};
+use lazy_static::lazy_static;
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::calibration::{get_transform, Affine, CalibrationInput};
+use crate::devices::{detect_device, AxisRange, Digitizer};
+use crate::orientation::current_orientation;
+use crate::pointer::PenState;
+use crate::MyBackend;
+
+/// Sent to the client once the device owner answered its request: the packet type, then 1 if
+/// the remote control was granted, 0 otherwise.
+pub const REMOTE_INPUT_PACKET: u8 = 10;
+
+/// Client message asking for the remote control. No payload.
+pub const CLIENT_REMOTE_INPUT_REQUEST: u8 = 1;
+/// Client message with a pen sample: the position on the screen (0.0 - 1.0, positions outside of it are
+/// moved onto the edge) as big-endian f32s, the `PenState` id (only out of range, hovering and touching are accepted),
+/// then the pressure (0.0 - 1.0) as an f32.
+pub const CLIENT_REMOTE_INPUT_EVENT: u8 = 2;
+
+/// How long the device owner has to answer a request before it's denied.
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(30);
+/// Events above this rate are dropped.
+const MAX_EVENTS_PER_SECOND: u32 = 120;
+/// Beyond `MAX_EVENTS_PER_SECOND`, pen-down and the other state changes still get through up to this rate.
+/// Lifting the pen always gets through, so that a stroke isn't left half-finished.
+const MAX_STATE_CHANGES_PER_SECOND: u32 = 10;
+
+/// Messages sent to the frontend: "request id,client address" to ask for approval,
+/// and "request id" to close the prompt once it's no longer relevant.
+const MSG_REMOTE_INPUT_REQUEST: u32 = 6;
+const MSG_REMOTE_INPUT_REQUEST_CLOSED: u32 = 7;
+
+lazy_static! {
+    /// Requests waiting for the device owner, along with the id of the next one.
+    static ref PENDING_APPROVALS: Mutex<(u32, HashMap<u32, oneshot::Sender<bool>>)> =
+        Mutex::new((0, HashMap::new()));
+    /// Created when the remote control is first granted.
+    static ref VIRTUAL_PEN: Mutex<Option<VirtualPen>> = Mutex::new(None);
+}
+
+/// A request shown to the device owner. Closes the prompt when dropped unanswered,
+/// whether nobody answered in time or the client is gone.
+struct PendingApproval {
+    id: u32,
+    replier: BackendReplier<MyBackend>,
+}
+
+impl Drop for PendingApproval {
+    fn drop(&mut self) {
+        if PENDING_APPROVALS
+            .lock()
+            .unwrap()
+            .1
+            .remove(&self.id)
+            .is_some()
+        {
+            let _ = self
+                .replier
+                .send_message(MSG_REMOTE_INPUT_REQUEST_CLOSED, &self.id.to_string());
+        }
+    }
+}
+
+/// Asks the device owner whether `client` may control the device. Denied if nobody answers in time.
+/// Dropping the future withdraws the request.
+pub async fn request_approval(replier: BackendReplier<MyBackend>, client: String) -> bool {
+    let (sender, receiver) = oneshot::channel();
+    let id = {
+        let mut pending = PENDING_APPROVALS.lock().unwrap();
+        let id = pending.0;
+        pending.0 = pending.0.wrapping_add(1);
+        pending.1.insert(id, sender);
+        id
+    };
+    println!("{client} is asking for the remote control");
+    let _ = replier.send_message(MSG_REMOTE_INPUT_REQUEST, &format!("{id},{client}"));
+    let _pending = PendingApproval { id, replier };
+    matches!(timeout(APPROVAL_TIMEOUT, receiver).await, Ok(Ok(true)))
+}
+
+/// Called with the frontend's answer: "request id,1" to allow, "request id,0" to deny.
+pub fn answer_approval(contents: &str) {
+    let Some((id, answer)) = contents
+        .split_once(',')
+        .and_then(|(id, answer)| Some((id.parse::<u32>().ok()?, answer == "1")))
+    else {
+        eprintln!("Invalid remote input answer: {contents}");
+        return;
+    };
+    if let Some(sender) = PENDING_APPROVALS.lock().unwrap().1.remove(&id) {
+        let _ = sender.send(answer);
+    }
+}
+
+/// A uinput pen mirroring the axes of the real digitizer.
+struct VirtualPen {
+    device: VirtualDevice,
+    digitizer: Digitizer,
+    default_transform: Affine,
+    state: PenState,
+}
+
+impl VirtualPen {
+    fn new() -> Result<Self> {
+        let device_info = detect_device()?;
+        let digitizer = device_info.find_digitizer()?;
+        let abs_setup = |code: AbsoluteAxisCode, range: AxisRange| {
+            UinputAbsSetup::new(
+                code,
+                AbsInfo::new(0, range.min as i32, range.max as i32, 0, 0, 0),
+            )
+        };
+        let mut keys = AttributeSet::<KeyCode>::new();
+        keys.insert(KeyCode::BTN_TOOL_PEN);
+        keys.insert(KeyCode::BTN_TOUCH);
+        let device = VirtualDevice::builder()?
+            .name("rmstream remote pen")
+            .with_keys(&keys)?
+            .with_absolute_axis(&abs_setup(AbsoluteAxisCode::ABS_X, digitizer.x))?
+            .with_absolute_axis(&abs_setup(AbsoluteAxisCode::ABS_Y, digitizer.y))?
+            .with_absolute_axis(&abs_setup(
+                AbsoluteAxisCode::ABS_PRESSURE,
+                digitizer.pressure,
+            ))?
+            .build()?;
+        Ok(Self {
+            device,
+            digitizer,
+            default_transform: device_info.digitizer_transform,
+            state: PenState::OutOfRange,
+        })
+    }
+
+    fn emit(&mut self, x: f32, y: f32, state: PenState, pressure: f32) -> Result<()> {
+        // The inverse of what update_pointer_pos_forever does with the real digitizer's data.
+        let orientation = current_orientation().transform().inverse().unwrap();
+        let transform = get_transform(CalibrationInput::Pen, self.default_transform)
+            .inverse()
+            .ok_or_else(|| anyhow!("The pen transform can't be inverted"))?;
+        let (x, y) = orientation.apply(f64::from(x), f64::from(y));
+        let (x, y) = transform.apply(x, y);
+        let touching = state == PenState::Touching;
+        let mut events: Vec<InputEvent> = vec![
+            *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_X, self.digitizer.x.denormalize(x)),
+            *AbsoluteAxisEvent::new(AbsoluteAxisCode::ABS_Y, self.digitizer.y.denormalize(y)),
+            *AbsoluteAxisEvent::new(
+                AbsoluteAxisCode::ABS_PRESSURE,
+                if touching {
+                    self.digitizer
+                        .pressure
+                        .denormalize(f64::from(pressure.clamp(0.0, 1.0)))
+                } else {
+                    0
+                },
+            ),
+        ];
+        if (self.state != PenState::OutOfRange) != (state != PenState::OutOfRange) {
+            events.push(*KeyEvent::new(
+                KeyCode::BTN_TOOL_PEN,
+                i32::from(state != PenState::OutOfRange),
+            ));
+        }
+        if (self.state == PenState::Touching) != touching {
+            events.push(*KeyEvent::new(KeyCode::BTN_TOUCH, i32::from(touching)));
+        }
+        self.state = state;
+        self.device.emit(&events)?;
+        Ok(())
+    }
+}
+
+/// The remote control granted to a single client.
+pub struct RemoteInput {
+    window_start: Instant,
+    events_in_window: u32,
+    state_changes_in_window: u32,
+    previous_state: PenState,
+    previous_position: (f32, f32),
+}
+
+impl Default for RemoteInput {
+    fn default() -> Self {
+        Self {
+            window_start: Instant::now(),
+            events_in_window: 0,
+            state_changes_in_window: 0,
+            previous_state: PenState::OutOfRange,
+            previous_position: (0.0, 0.0),
+        }
+    }
+}
+
+impl RemoteInput {
+    /// Handles a `CLIENT_REMOTE_INPUT_EVENT` message, without the type byte.
+    pub fn handle_event(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() < 13 {
+            return Err(anyhow!("Remote input event too short"));
+        }
+        let f32_at =
+            |i: usize| f32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let (x, y, pressure) = (f32_at(0), f32_at(4), f32_at(9));
+        let state = match data[8] {
+            0 => PenState::OutOfRange,
+            1 => PenState::Hovering,
+            2 => PenState::Touching,
+            state => return Err(anyhow!("Unsupported remote pen state {state}")),
+        };
+        if !x.is_finite() || !y.is_finite() {
+            return Err(anyhow!("Invalid remote pen position {x}, {y}"));
+        }
+        // The pen can leave the viewer's canvas, for example mid-stroke. Its state still has to be applied.
+        let (x, y) = (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0));
+
+        if !self.allow(state) {
+            return Ok(());
+        }
+        self.previous_state = state;
+        self.previous_position = (x, y);
+
+        let mut pen = VIRTUAL_PEN.lock().unwrap();
+        if pen.is_none() {
+            *pen = Some(VirtualPen::new()?);
+        }
+        pen.as_mut().unwrap().emit(x, y, state, pressure)
+    }
+
+    /// Counts the event against the rate limit, and returns whether it can be emitted.
+    fn allow(&mut self, state: PenState) -> bool {
+        if self.window_start.elapsed() >= Duration::from_secs(1) {
+            self.window_start = Instant::now();
+            self.events_in_window = 0;
+            self.state_changes_in_window = 0;
+        }
+        self.events_in_window += 1;
+        if self.events_in_window <= MAX_EVENTS_PER_SECOND {
+            return true;
+        }
+        if state == self.previous_state {
+            return false;
+        }
+        if matches!(state, PenState::Hovering | PenState::OutOfRange) {
+            return true;
+        }
+        self.state_changes_in_window += 1;
+        self.state_changes_in_window <= MAX_STATE_CHANGES_PER_SECOND
+    }
+}
+
+impl Drop for RemoteInput {
+    fn drop(&mut self) {
+        // Don't leave the pen pressed down if the client goes away mid-stroke.
+        if self.previous_state != PenState::OutOfRange {
+            if let Some(pen) = VIRTUAL_PEN.lock().unwrap().as_mut() {
+                let (x, y) = self.previous_position;
+                let _ = pen.emit(x, y, PenState::OutOfRange, 0.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds the states to a fresh `RemoteInput`, as `handle_event` would, and counts the emitted ones.
+    fn allowed(states: impl Iterator<Item = PenState>) -> usize {
+        let mut input = RemoteInput::default();
+        states
+            .filter(|state| {
+                let allowed = input.allow(*state);
+                if allowed {
+                    input.previous_state = *state;
+                }
+                allowed
+            })
+            .count()
+    }
+
+    #[test]
+    fn limits_every_event() {
+        let moves = std::iter::repeat_n(PenState::Touching, 1000);
+        assert_eq!(allowed(moves), MAX_EVENTS_PER_SECOND as usize);
+        // Alternating states doesn't get past the limit either.
+        let alternating = [PenState::Hovering, PenState::Touching]
+            .into_iter()
+            .cycle()
+            .take(1000);
+        // Only pen-downs count against the state changes, and the last one is still lifted.
+        assert_eq!(
+            allowed(alternating),
+            (MAX_EVENTS_PER_SECOND + 2 * MAX_STATE_CHANGES_PER_SECOND + 1) as usize
+        );
+    }
+
+    #[test]
+    fn state_changes_get_past_a_flood_of_moves() {
+        let states = std::iter::repeat_n(PenState::Touching, 500).chain([PenState::Hovering]);
+        assert_eq!(allowed(states), MAX_EVENTS_PER_SECOND as usize + 1);
+    }
+
+    #[test]
+    fn lifting_the_pen_always_gets_through() {
+        let mut input = RemoteInput::default();
+        for _ in 0..MAX_EVENTS_PER_SECOND {
+            assert!(input.allow(PenState::Hovering));
+        }
+        // Use up the state changes, ending with the pen down.
+        for _ in 0..MAX_STATE_CHANGES_PER_SECOND {
+            input.previous_state = PenState::Hovering;
+            assert!(input.allow(PenState::Touching));
+            input.previous_state = PenState::Touching;
+        }
+        assert!(input.allow(PenState::Hovering));
+        input.previous_state = PenState::Hovering;
+        assert!(!input.allow(PenState::Touching));
+        assert!(input.allow(PenState::OutOfRange));
+    }
+}
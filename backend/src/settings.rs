@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::codec::{Codec, CodecSettings};
+use crate::damage::CaptureMode;
+use crate::frames::KeyframeFormat;
+use crate::orientation::Orientation;
+
+pub const CONFIG_DIRECTORY: &str = "/home/root/.config/rmstream";
+const SETTINGS_FILE: &str = "/home/root/.config/rmstream/settings.conf";
+
+pub struct Settings {
+    /// How long the framebuffer has to stay unchanged before a large change is broadcast.
+    /// A zero window disables settling altogether.
+    pub settle_window: Duration,
+    /// Changes producing fewer delta bytes than this are broadcast immediately.
+    pub settle_threshold: usize,
+    /// Upper bound on how long a large change can be held back if the screen keeps changing.
+    pub settle_max_hold: Duration,
+    /// Codec used for clients which don't ask for one, and as the reference for keyframe decisions.
+    pub default_codec: CodecSettings,
+    /// Keyframe format used for clients which don't ask for one, and as the reference for keyframe decisions.
+    pub default_keyframe_format: KeyframeFormat,
+    /// Overrides the detected screen orientation. `None` uses the one reported by framebuffer-spy.
+    /// Also picks the pointer calibration.
+    pub orientation: Option<Orientation>,
+    /// Where the strokes of every finished page, and of the last one at the end of a session, are exported.
+    /// `None` disables the export.
+    pub stroke_export_directory: Option<PathBuf>,
+    /// Whether viewers may ask for the remote control. Every request still has to be approved on the device.
+    pub remote_input: bool,
+    /// `experimental-damage` reads only the damaged rows. Nothing sends the damage reports it needs yet.
+    pub capture_mode: CaptureMode,
+    /// How often the whole framebuffer is still read in `CaptureMode::Damage`, in case a report was missed.
+    pub damage_fallback: Duration,
+    /// A shared-memory framebuffer export to capture instead of xochitl's memory.
+    pub shared_memory: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            settle_window: Duration::from_millis(250),
+            settle_threshold: 64 * 1024,
+            settle_max_hold: Duration::from_millis(2000),
+            default_codec: CodecSettings::new(Codec::Deflate, None),
+            default_keyframe_format: KeyframeFormat::Png,
+            orientation: None,
+            stroke_export_directory: Some(PathBuf::from("/home/root/rmstream-strokes")),
+            remote_input: false,
+            capture_mode: CaptureMode::Poll,
+            damage_fallback: Duration::from_millis(1000),
+            shared_memory: None,
+        }
+    }
+}
+
+/// Reads `key = value` lines. Empty lines and lines starting with `#` are skipped.
+pub fn parse_settings_file(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+fn get_or<T: FromStr>(values: &HashMap<String, String>, key: &str, default: T) -> T {
+    match values.get(key).map(|e| e.parse()) {
+        Some(Ok(value)) => value,
+        Some(Err(_)) => {
+            eprintln!("Invalid value for setting {key}. Using the default.");
+            default
+        }
+        None => default,
+    }
+}
+
+impl Settings {
+    fn load() -> Self {
+        let values = std::fs::read_to_string(SETTINGS_FILE)
+            .map(|e| parse_settings_file(&e))
+            .unwrap_or_default();
+        let default = Self::default();
+        Self {
+            settle_window: Duration::from_millis(get_or(
+                &values,
+                "settle_window_ms",
+                default.settle_window.as_millis() as u64,
+            )),
+            settle_threshold: get_or(&values, "settle_threshold", default.settle_threshold),
+            settle_max_hold: Duration::from_millis(get_or(
+                &values,
+                "settle_max_hold_ms",
+                default.settle_max_hold.as_millis() as u64,
+            )),
+            default_codec: match values.get("codec").map(|e| Codec::from_name(e)) {
+                Some(Some(codec)) => CodecSettings::new(
+                    codec,
+                    values.get("codec_level").and_then(|e| e.parse().ok()),
+                ),
+                Some(None) => {
+                    eprintln!("Unknown codec. Using the default.");
+                    default.default_codec
+                }
+                None => CodecSettings::new(
+                    default.default_codec.codec,
+                    values.get("codec_level").and_then(|e| e.parse().ok()),
+                ),
+            },
+            default_keyframe_format: get_or(
+                &values,
+                "keyframe_format",
+                default.default_keyframe_format,
+            ),
+            orientation: match values.get("orientation").map(String::as_str) {
+                None | Some("auto") => default.orientation,
+                Some(orientation) => match orientation.parse() {
+                    Ok(orientation) => Some(orientation),
+                    Err(_) => {
+                        eprintln!(
+                            "The orientation has to be auto, 0, 90, 180 or 270. Using the default."
+                        );
+                        default.orientation
+                    }
+                },
+            },
+            stroke_export_directory: match values.get("stroke_export_directory") {
+                Some(directory) if directory.is_empty() => None,
+                Some(directory) => Some(PathBuf::from(directory)),
+                None => default.stroke_export_directory,
+            },
+            remote_input: get_or(&values, "remote_input", default.remote_input),
+            capture_mode: get_or(&values, "capture", default.capture_mode),
+            damage_fallback: Duration::from_millis(get_or(
+                &values,
+                "damage_fallback_ms",
+                default.damage_fallback.as_millis() as u64,
+            )),
+            shared_memory: match values.get("shared_memory") {
+                Some(path) if path.is_empty() => None,
+                Some(path) => Some(PathBuf::from(path)),
+                None => default.shared_memory,
+            },
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref SETTINGS: Settings = Settings::load();
+}
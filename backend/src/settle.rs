@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+/// Holds back large changes until the e-ink waveform has finished drawing them.
+///
+/// Changes producing no more than `threshold` delta bytes (pen strokes) go out immediately.
+/// Larger ones are held until the screen stopped changing for `window`, or for at most `max_hold`.
+pub struct Settler {
+    window: Duration,
+    max_hold: Duration,
+    threshold: usize,
+    last_change: Instant,
+    settling_since: Option<Instant>,
+}
+
+impl Settler {
+    pub fn new(window: Duration, max_hold: Duration, threshold: usize, now: Instant) -> Self {
+        Self {
+            window,
+            max_hold,
+            threshold,
+            last_change: now,
+            settling_since: None,
+        }
+    }
+
+    /// Whether a change is being held back. The screen has to be polled until it settles.
+    pub fn is_settling(&self) -> bool {
+        self.settling_since.is_some()
+    }
+
+    /// Records that the screen changed since the last poll.
+    pub fn changed(&mut self, now: Instant) {
+        self.last_change = now;
+    }
+
+    /// Whether the held back change still has to wait.
+    pub fn is_holding(&self, now: Instant) -> bool {
+        match self.settling_since {
+            Some(since) => now - self.last_change < self.window && now - since < self.max_hold,
+            None => false,
+        }
+    }
+
+    /// Decides whether deltas of this size are sent now. Otherwise, they start to be held back.
+    pub fn should_send(&mut self, delta_size: usize, now: Instant) -> bool {
+        if delta_size == 0 {
+            self.settling_since = None;
+            return false;
+        }
+        if self.settling_since.is_none() && delta_size > self.threshold && !self.window.is_zero() {
+            self.settling_since = Some(now);
+            return false;
+        }
+        self.settling_since = None;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(250);
+    const MAX_HOLD: Duration = Duration::from_millis(2000);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn sends_small_changes_immediately() {
+        let start = Instant::now();
+        let mut settler = Settler::new(WINDOW, MAX_HOLD, 100, start);
+        settler.changed(start);
+        assert!(settler.should_send(100, start));
+        assert!(!settler.is_settling());
+    }
+
+    #[test]
+    fn holds_large_changes_until_the_screen_settles() {
+        let start = Instant::now();
+        let mut settler = Settler::new(WINDOW, MAX_HOLD, 100, start);
+        settler.changed(start);
+        assert!(!settler.should_send(101, start));
+        assert!(settler.is_settling());
+        // The waveform keeps redrawing the screen.
+        settler.changed(start + ms(200));
+        assert!(settler.is_holding(start + ms(300)));
+        assert!(!settler.is_holding(start + ms(450)));
+        // Once settled, the change goes out, however large it is.
+        assert!(settler.should_send(10_000, start + ms(450)));
+        assert!(!settler.is_settling());
+    }
+
+    #[test]
+    fn holds_at_most_max_hold() {
+        let start = Instant::now();
+        let mut settler = Settler::new(WINDOW, MAX_HOLD, 100, start);
+        assert!(!settler.should_send(101, start));
+        for elapsed in (0..2000).step_by(100) {
+            settler.changed(start + ms(elapsed));
+            assert!(settler.is_holding(start + ms(elapsed)));
+        }
+        settler.changed(start + ms(2000));
+        assert!(!settler.is_holding(start + ms(2000)));
+    }
+
+    #[test]
+    fn a_zero_window_disables_settling() {
+        let start = Instant::now();
+        let mut settler = Settler::new(Duration::ZERO, MAX_HOLD, 100, start);
+        assert!(settler.should_send(10_000, start));
+        assert!(!settler.is_holding(start));
+    }
+
+    #[test]
+    fn changes_which_undo_themselves_end_the_hold() {
+        let start = Instant::now();
+        let mut settler = Settler::new(WINDOW, MAX_HOLD, 100, start);
+        assert!(!settler.should_send(101, start));
+        assert!(!settler.should_send(0, start + ms(300)));
+        assert!(!settler.is_settling());
+    }
+}
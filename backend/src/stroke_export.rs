@@ -0,0 +1,205 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::settings::SETTINGS;
+use crate::strokes::{current_page, Stroke};
+
+/// Stroke width in pixels at full pressure.
+const MAX_STROKE_WIDTH: f32 = 6.0;
+
+/// The size of the screen, set once the framebuffer is known.
+pub static PAGE_SIZE: OnceLock<(u32, u32)> = OnceLock::new();
+/// When the session started, in seconds since the epoch. Names the files of all its pages.
+static SESSION: OnceLock<u64> = OnceLock::new();
+/// The number of pages exported in this session.
+static EXPORTED_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Renders the strokes as an SVG image the size of the screen.
+pub fn render_svg(strokes: &[Stroke], width: u32, height: u32) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+    );
+    let _ = writeln!(
+        out,
+        r#"<g fill="none" stroke="black" stroke-linecap="round" stroke-linejoin="round">"#
+    );
+    for stroke in strokes {
+        if stroke.is_empty() {
+            continue;
+        }
+        // SVG paths have a single width, so the pressure is averaged over the stroke.
+        let pressure = stroke.iter().map(|e| e.pressure).sum::<f32>() / stroke.len() as f32;
+        let mut path = String::new();
+        for (i, point) in stroke.iter().enumerate() {
+            let _ = write!(
+                path,
+                "{}{:.1},{:.1} ",
+                if i == 0 { 'M' } else { 'L' },
+                point.x * width as f32,
+                point.y * height as f32
+            );
+        }
+        let _ = writeln!(
+            out,
+            r#"<path d="{}" stroke-width="{:.2}"/>"#,
+            path.trim_end(),
+            (pressure * MAX_STROKE_WIDTH).max(1.0)
+        );
+    }
+    out.push_str("</g>\n</svg>\n");
+    out
+}
+
+/// Renders the strokes as an InkML document, with the position in screen pixels and the pressure as the force.
+pub fn render_inkml(strokes: &[Stroke], width: u32, height: u32) -> String {
+    let mut out = String::new();
+    out.push_str(concat!(
+        "<ink xmlns=\"http://www.w3.org/2003/InkML\">\n",
+        "<definitions>\n",
+        "<context xml:id=\"ctx0\">\n",
+        "<inkSource xml:id=\"inkSrc0\">\n",
+        "<traceFormat>\n",
+        "<channel name=\"X\" type=\"decimal\" units=\"dev\"/>\n",
+        "<channel name=\"Y\" type=\"decimal\" units=\"dev\"/>\n",
+        "<channel name=\"F\" type=\"decimal\" min=\"0\" max=\"1\"/>\n",
+        "</traceFormat>\n",
+        "</inkSource>\n",
+        "</context>\n",
+        "</definitions>\n",
+    ));
+    for stroke in strokes {
+        let points = stroke
+            .iter()
+            .map(|point| {
+                format!(
+                    "{:.1} {:.1} {:.3}",
+                    point.x * width as f32,
+                    point.y * height as f32,
+                    point.pressure
+                )
+            })
+            .collect::<Vec<_>>();
+        let _ = writeln!(
+            out,
+            "<trace contextRef=\"#ctx0\">{}</trace>",
+            points.join(", ")
+        );
+    }
+    out.push_str("</ink>\n");
+    out
+}
+
+/// Writes the page as `session-<session>-page-<number>.svg` and `.inkml` into `directory`.
+fn write_page(
+    directory: &Path,
+    session: u64,
+    number: usize,
+    page: &[Stroke],
+    (width, height): (u32, u32),
+) -> Result<()> {
+    std::fs::create_dir_all(directory)?;
+    let path = directory.join(format!("session-{session}-page-{number}"));
+    std::fs::write(path.with_extension("svg"), render_svg(page, width, height))?;
+    std::fs::write(
+        path.with_extension("inkml"),
+        render_inkml(page, width, height),
+    )?;
+    Ok(())
+}
+
+/// Writes a page into the export directory, numbered in the order the pages were finished.
+pub fn export_page(page: &[Stroke]) -> Result<()> {
+    let (Some(directory), Some(&size)) = (&SETTINGS.stroke_export_directory, PAGE_SIZE.get())
+    else {
+        return Ok(());
+    };
+    if page.is_empty() {
+        return Ok(());
+    }
+    let session = *SESSION.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |e| e.as_secs())
+    });
+    let number = EXPORTED_PAGES.fetch_add(1, Ordering::Relaxed) + 1;
+    write_page(directory, session, number, page, size)
+}
+
+/// Writes the page still being drawn when the session ends. The earlier ones were exported as they were finished.
+pub fn export_session() -> Result<()> {
+    export_page(&current_page())?;
+    if let Some(directory) = &SETTINGS.stroke_export_directory {
+        let pages = EXPORTED_PAGES.load(Ordering::Relaxed);
+        if pages != 0 {
+            println!(
+                "Exported {pages} page(s) of strokes to {}",
+                directory.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strokes::StrokePoint;
+
+    fn stroke(points: &[(f32, f32, f32)]) -> Stroke {
+        points
+            .iter()
+            .map(|&(x, y, pressure)| StrokePoint { x, y, pressure })
+            .collect()
+    }
+
+    #[test]
+    fn renders_svg_paths_in_pixels() {
+        let svg = render_svg(
+            &[stroke(&[(0.0, 0.0, 0.5), (0.5, 1.0, 1.0)]), stroke(&[])],
+            200,
+            100,
+        );
+        assert!(
+            svg.starts_with(r#"<svg xmlns="http://www.w3.org/2000/svg" width="200" height="100""#)
+        );
+        // The empty stroke is left out, and the width follows the average pressure.
+        assert_eq!(svg.matches("<path").count(), 1);
+        assert!(
+            svg.contains(r#"<path d="M0.0,0.0 L100.0,100.0" stroke-width="4.50"/>"#),
+            "{svg}"
+        );
+        assert!(svg.ends_with("</svg>\n"));
+    }
+
+    #[test]
+    fn renders_inkml_traces_with_the_pressure() {
+        let inkml = render_inkml(&[stroke(&[(0.25, 0.5, 0.125), (1.0, 0.0, 1.0)])], 200, 100);
+        assert!(inkml.starts_with("<ink xmlns=\"http://www.w3.org/2003/InkML\">"));
+        assert!(
+            inkml.contains("<trace contextRef=\"#ctx0\">50.0 50.0 0.125, 200.0 0.0 1.000</trace>"),
+            "{inkml}"
+        );
+        assert!(inkml.ends_with("</ink>\n"));
+    }
+
+    #[test]
+    fn writes_both_files_of_a_page() {
+        let directory =
+            std::env::temp_dir().join(format!("rmstream-export-{}", std::process::id()));
+        let page = [stroke(&[(0.0, 0.0, 1.0), (1.0, 1.0, 1.0)])];
+        write_page(&directory, 42, 3, &page, (10, 10)).unwrap();
+        let path = directory.join("session-42-page-3");
+        let svg = std::fs::read_to_string(path.with_extension("svg")).unwrap();
+        let inkml = std::fs::read_to_string(path.with_extension("inkml")).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(svg, render_svg(&page, 10, 10));
+        assert_eq!(inkml, render_inkml(&page, 10, 10));
+    }
+}
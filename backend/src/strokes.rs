@@ -0,0 +1,242 @@
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::pointer::{PenState, PointerState};
+use crate::stroke_export::export_page;
+
+/// One sample of a pen stroke: the packet type, the stroke id as a u32, the `StrokePhase` id,
+/// then the position (0.0 - 1.0) and the pressure (0.0 - 1.0) as big-endian f32s.
+pub const STROKE_PACKET: u8 = 9;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StrokePhase {
+    /// The pen touched the screen. The sample is the first point of the stroke.
+    Begin,
+    Point,
+    /// The pen was lifted. The sample repeats the last point of the stroke.
+    End,
+}
+
+impl StrokePhase {
+    pub fn id(self) -> u8 {
+        match self {
+            StrokePhase::Begin => 0,
+            StrokePhase::Point => 1,
+            StrokePhase::End => 2,
+        }
+    }
+}
+
+/// Frame changes touching more than this fraction of the screen are treated as a new page.
+const NEW_PAGE_CHANGE_FRACTION: f64 = 0.05;
+
+#[derive(Clone, Copy)]
+pub struct StrokePoint {
+    /// Horizontal position on the screen, from 0.0 to 1.0.
+    pub x: f32,
+    /// Vertical position on the screen, from 0.0 to 1.0.
+    pub y: f32,
+    /// From 0.0 to 1.0.
+    pub pressure: f32,
+}
+
+pub type Stroke = Vec<StrokePoint>;
+
+/// The strokes of the page being drawn. Finished pages are handed to the export.
+#[derive(Default)]
+struct StrokeStore {
+    current_page: Vec<Stroke>,
+    /// The stroke being drawn. Added to the current page once the pen is lifted.
+    current_stroke: Option<Stroke>,
+}
+
+impl StrokeStore {
+    /// Returns the strokes of the current page, including the one being drawn.
+    fn current_page(&self) -> Vec<Stroke> {
+        self.current_page
+            .iter()
+            .chain(&self.current_stroke)
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the finished page, if the change starts a new one.
+    fn frame_changed(&mut self, changed_bytes: usize, frame_bytes: usize) -> Option<Vec<Stroke>> {
+        if (changed_bytes as f64) < frame_bytes as f64 * NEW_PAGE_CHANGE_FRACTION
+            || self.current_page.is_empty()
+        {
+            return None;
+        }
+        Some(std::mem::take(&mut self.current_page))
+    }
+
+    fn record(&mut self, phase: StrokePhase, sample: &PointerState) {
+        let point = StrokePoint {
+            x: sample.x,
+            y: sample.y,
+            pressure: sample.pressure,
+        };
+        match phase {
+            StrokePhase::Begin => self.current_stroke = Some(vec![point]),
+            StrokePhase::Point => {
+                if let Some(stroke) = &mut self.current_stroke {
+                    stroke.push(point);
+                }
+            }
+            StrokePhase::End => {
+                if let Some(stroke) = self.current_stroke.take() {
+                    self.current_page.push(stroke);
+                }
+            }
+        }
+    }
+}
+
+lazy_static! {
+    static ref STROKES: Mutex<StrokeStore> = Mutex::new(StrokeStore::default());
+}
+
+/// Returns the strokes of the current page, including the one being drawn.
+pub fn current_page() -> Vec<Stroke> {
+    STROKES.lock().unwrap().current_page()
+}
+
+/// Called for every frame sent to the clients. Starts a new page if a large part of the screen changed,
+/// as it does when turning pages or opening another document. The finished page is exported right away,
+/// so that no page is lost however long the session is.
+pub fn frame_changed(changed_bytes: usize, frame_bytes: usize) {
+    let page = STROKES
+        .lock()
+        .unwrap()
+        .frame_changed(changed_bytes, frame_bytes);
+    if let Some(page) = page {
+        if let Err(e) = export_page(&page) {
+            eprintln!("Failed to export the strokes of a page: {e:?}");
+        }
+    }
+}
+
+/// Turns the stream of pointer states into strokes, from pen-down to pen-up, and records them.
+/// The eraser doesn't produce strokes - the raster frames show what was erased.
+#[derive(Default)]
+pub struct StrokeTracker {
+    next_id: u32,
+    /// The id of the stroke being drawn, and its last sample.
+    current: Option<(u32, PointerState)>,
+}
+
+impl StrokeTracker {
+    /// Returns the stroke packet for the new pointer state, if it belongs to a stroke.
+    pub fn update(&mut self, state: &PointerState) -> Option<Vec<u8>> {
+        let (id, phase, sample) = match (self.current, state.state == PenState::Touching) {
+            (None, false) => return None,
+            (None, true) => {
+                let id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+                self.current = Some((id, *state));
+                (id, StrokePhase::Begin, *state)
+            }
+            (Some((id, _)), true) => {
+                self.current = Some((id, *state));
+                (id, StrokePhase::Point, *state)
+            }
+            (Some((id, last)), false) => {
+                self.current = None;
+                (id, StrokePhase::End, last)
+            }
+        };
+        STROKES.lock().unwrap().record(phase, &sample);
+        let mut packet = vec![STROKE_PACKET];
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.push(phase.id());
+        packet.extend_from_slice(&sample.x.to_be_bytes());
+        packet.extend_from_slice(&sample.y.to_be_bytes());
+        packet.extend_from_slice(&sample.pressure.to_be_bytes());
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pointer::PenTool;
+
+    fn state(x: f32, state: PenState) -> PointerState {
+        PointerState {
+            x,
+            y: 0.5,
+            state,
+            tool: PenTool::Pen,
+            distance: 0.0,
+            pressure: 0.5,
+            tilt_x: 0.0,
+            tilt_y: 0.0,
+        }
+    }
+
+    #[test]
+    fn turns_pen_down_to_pen_up_into_a_stroke() {
+        let mut tracker = StrokeTracker::default();
+        let phases = [
+            PenState::Hovering,
+            PenState::Touching,
+            PenState::Touching,
+            PenState::Hovering,
+            PenState::Erasing,
+            PenState::Touching,
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, pen_state)| {
+            tracker
+                .update(&state(i as f32 / 10.0, pen_state))
+                .map(|packet| {
+                    (
+                        u32::from_be_bytes(packet[1..5].try_into().unwrap()),
+                        packet[5],
+                    )
+                })
+        })
+        .collect::<Vec<_>>();
+        use StrokePhase::*;
+        assert_eq!(
+            phases,
+            [
+                None,
+                Some((0, Begin.id())),
+                Some((0, Point.id())),
+                Some((0, End.id())),
+                // The eraser doesn't draw.
+                None,
+                Some((1, Begin.id())),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_end_repeats_the_last_point() {
+        let mut tracker = StrokeTracker::default();
+        tracker.update(&state(0.25, PenState::Touching));
+        let end = tracker.update(&state(0.75, PenState::Hovering)).unwrap();
+        assert_eq!(end[6..10], 0.25f32.to_be_bytes());
+    }
+
+    #[test]
+    fn large_changes_finish_the_page() {
+        let mut store = StrokeStore::default();
+        store.record(StrokePhase::Begin, &state(0.1, PenState::Touching));
+        store.record(StrokePhase::Point, &state(0.2, PenState::Touching));
+        // The stroke being drawn is part of the current page, but not of a finished one yet.
+        assert_eq!(store.current_page().len(), 1);
+        assert!(store.frame_changed(1000, 1000).is_none());
+        store.record(StrokePhase::End, &state(0.2, PenState::Hovering));
+        // Drawing only changes a small part of the screen.
+        assert!(store.frame_changed(49, 1000).is_none());
+        let page = store.frame_changed(50, 1000).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].len(), 2);
+        assert!(store.current_page().is_empty());
+        assert!(store.frame_changed(1000, 1000).is_none());
+    }
+}
@@ -0,0 +1,196 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use evdev::{AbsoluteAxisCode, Device, EventSummary, SynchronizationCode};
+
+use crate::calibration::{get_transform, record_calibration_point, Affine, CalibrationInput};
+use crate::devices::detect_device;
+use crate::orientation::current_orientation;
+use crate::{Packet, POINTER_BROADCASTER};
+
+/// The number of contacts as a u8, followed by (tracking id: u16 BE, x: f32 BE, y: f32 BE)
+/// for each finger on the screen. Positions are normalized to the 0.0 - 1.0 range.
+pub const TOUCH_PACKET: u8 = 7;
+
+#[derive(Clone, Copy, Default)]
+struct Slot {
+    /// Set by the kernel for every new contact. `None` once the finger is lifted.
+    tracking_id: Option<u16>,
+    x: i32,
+    y: i32,
+}
+
+/// The contacts, as tracked through the multitouch slots.
+struct Contacts {
+    slots: Vec<Slot>,
+    current_slot: usize,
+}
+
+impl Contacts {
+    fn new(slots: usize) -> Self {
+        Self {
+            slots: vec![Slot::default(); slots],
+            current_slot: 0,
+        }
+    }
+
+    /// Applies an absolute axis event. Axes which don't concern the contacts are ignored.
+    fn update(&mut self, code: AbsoluteAxisCode, value: i32) {
+        match code {
+            AbsoluteAxisCode::ABS_MT_SLOT => {
+                self.current_slot = value.max(0) as usize;
+                if self.current_slot >= self.slots.len() {
+                    self.slots.resize(self.current_slot + 1, Slot::default());
+                }
+            }
+            AbsoluteAxisCode::ABS_MT_TRACKING_ID => {
+                self.slots[self.current_slot].tracking_id = (value >= 0).then_some(value as u16);
+            }
+            AbsoluteAxisCode::ABS_MT_POSITION_X => self.slots[self.current_slot].x = value,
+            AbsoluteAxisCode::ABS_MT_POSITION_Y => self.slots[self.current_slot].y = value,
+            _ => {}
+        }
+    }
+
+    /// The fingers on the screen, as (tracking id, x, y) in the touchscreen's own coordinates.
+    fn active(&self) -> Vec<(u16, i32, i32)> {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.tracking_id.map(|id| (id, slot.x, slot.y)))
+            .collect()
+    }
+}
+
+/// Maps a normalized touchscreen position onto the streamed screen.
+fn to_screen(position: (f64, f64), transform: &Affine, orientation: &Affine) -> (f64, f64) {
+    let (x, y) = transform.apply(position.0, position.1);
+    orientation.apply(x, y)
+}
+
+fn serialize(contacts: &[(u16, (f64, f64))]) -> Vec<u8> {
+    let mut packet = vec![TOUCH_PACKET, contacts.len() as u8];
+    for (id, (x, y)) in contacts {
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&(*x as f32).to_be_bytes());
+        packet.extend_from_slice(&(*y as f32).to_be_bytes());
+    }
+    packet
+}
+
+/// Reads the multitouch slots of the touchscreen and broadcasts the active contacts.
+pub async fn update_touch_forever() -> Result<()> {
+    let device_info = detect_device()?;
+    let touchscreen = device_info.find_touchscreen()?;
+    println!("Using touchscreen {touchscreen:?}");
+    let mut evdev_device = Device::open(&touchscreen.path)?.into_event_stream()?;
+    let mut contacts = Contacts::new(touchscreen.slots);
+    let mut previous_packet = vec![TOUCH_PACKET, 0];
+    let mut previous_ids = Vec::new();
+    loop {
+        let event = evdev_device.next_event().await?;
+        match event.destructure() {
+            EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _) => {
+                let transform = get_transform(CalibrationInput::Touch, device_info.touch_transform);
+                let orientation = current_orientation().transform();
+                let active = contacts
+                    .active()
+                    .into_iter()
+                    .map(|(id, x, y)| {
+                        let position = touchscreen.normalize_position(x, y);
+                        if !previous_ids.contains(&id) {
+                            record_calibration_point(
+                                CalibrationInput::Touch,
+                                position.0,
+                                position.1,
+                            );
+                        }
+                        (id, to_screen(position, &transform, &orientation))
+                    })
+                    .collect::<Vec<_>>();
+                previous_ids = active.iter().map(|(id, _)| *id).collect();
+                let packet = serialize(&active);
+                if packet == previous_packet {
+                    continue;
+                }
+                previous_packet = packet.clone();
+                let _ = POINTER_BROADCASTER
+                    .lock()
+                    .await
+                    .send(Packet::Touch(Arc::new(packet)));
+            }
+            EventSummary::AbsoluteAxis(_, code, value) => contacts.update(code, value),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::devices::{AxisRange, Touchscreen};
+    use crate::orientation::Orientation;
+
+    #[test]
+    fn tracks_contacts_through_their_slots() {
+        let mut contacts = Contacts::new(2);
+        for (code, value) in [
+            (AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            (AbsoluteAxisCode::ABS_MT_TRACKING_ID, 7),
+            (AbsoluteAxisCode::ABS_MT_POSITION_X, 10),
+            (AbsoluteAxisCode::ABS_MT_POSITION_Y, 20),
+            // Slots beyond the reported count are still tracked.
+            (AbsoluteAxisCode::ABS_MT_SLOT, 3),
+            (AbsoluteAxisCode::ABS_MT_TRACKING_ID, 8),
+            (AbsoluteAxisCode::ABS_MT_POSITION_X, 30),
+        ] {
+            contacts.update(code, value);
+        }
+        assert_eq!(contacts.active(), [(7, 10, 20), (8, 30, 0)]);
+        contacts.update(AbsoluteAxisCode::ABS_MT_SLOT, 0);
+        contacts.update(AbsoluteAxisCode::ABS_MT_TRACKING_ID, -1);
+        assert_eq!(contacts.active(), [(8, 30, 0)]);
+    }
+
+    #[test]
+    fn maps_contacts_onto_the_screen() {
+        let touchscreen = Touchscreen {
+            path: PathBuf::new(),
+            x: AxisRange {
+                min: 0.0,
+                max: 1000.0,
+            },
+            y: AxisRange {
+                min: 100.0,
+                max: 2100.0,
+            },
+            slots: 1,
+        };
+        let position = touchscreen.normalize_position(250, 600);
+        assert_eq!(position, (0.25, 0.25));
+        // Both axes flipped, as on the reMarkable 2.
+        let flipped = Affine([-1.0, 0.0, 1.0, 0.0, -1.0, 1.0]);
+        let upright = Orientation::Upright.transform();
+        assert_eq!(to_screen(position, &flipped, &upright), (0.75, 0.75));
+        let (x, y) = to_screen(
+            position,
+            &Affine::IDENTITY,
+            &Orientation::Clockwise90.transform(),
+        );
+        assert!(
+            (x - 0.75).abs() < 1e-9 && (y - 0.25).abs() < 1e-9,
+            "{x}, {y}"
+        );
+    }
+
+    #[test]
+    fn serializes_every_contact() {
+        let packet = serialize(&[(1, (0.5, 0.25)), (2, (1.0, 0.0))]);
+        assert_eq!(packet.len(), 2 + 2 * 10);
+        assert_eq!(packet[..4], [TOUCH_PACKET, 2, 0, 1]);
+        assert_eq!(packet[4..8], 0.5f32.to_be_bytes());
+        assert_eq!(packet[8..12], 0.25f32.to_be_bytes());
+        assert_eq!(serialize(&[]), [TOUCH_PACKET, 0]);
+    }
+}
@@ -9,6 +9,10 @@ Rectangle {
     property var ips: []
     property var ready: false
     property var mainText: ''
+    property var calibrationTarget: null
+    property var remoteInputRequest: null
+    // Requests arriving while another one is on screen wait here, so an answer always goes to the client shown.
+    property var queuedRemoteInputRequests: []
 
     AppLoad {
         id: endpoint
@@ -26,6 +30,27 @@ Rectangle {
                 ready = toks[0] == '1' || toks[0] == 'true';
             } else if(type == 3) {
                 sendInit();
+            } else if(type == 4) {
+                let toks = contents.split(",");
+                calibrationTarget = { x: parseFloat(toks[0]), y: parseFloat(toks[1]) };
+                return;
+            } else if(type == 5) {
+                calibrationTarget = null;
+                mainText = contents;
+                return;
+            } else if(type == 6) {
+                let toks = contents.split(",");
+                queuedRemoteInputRequests = queuedRemoteInputRequests.concat([{ id: toks[0], client: toks[1] }]);
+                if(remoteInputRequest === null) {
+                    showNextRemoteInputRequest();
+                }
+                return;
+            } else if(type == 7) {
+                queuedRemoteInputRequests = queuedRemoteInputRequests.filter(e => e.id != contents);
+                if(remoteInputRequest && remoteInputRequest.id == contents) {
+                    showNextRemoteInputRequest();
+                }
+                return;
             }
             mainText = `The service is hosted on:\n${ips.map(e => '- ' + e).join('\n')}\nThe service is${ready ? '' : ' NOT'} running.`;
         }
@@ -33,7 +58,12 @@ Rectangle {
 
     XoviMessageBroker {
         id: broker
-        listeningFor: []
+        // Damaged regions ("x,y,width,height") are relayed to the backend, which can then read only the rows
+        // that changed. Released framebuffer-spy versions don't send this signal yet.
+        listeningFor: ["framebuffer-spy$damage"]
+        onSignalReceived: (signal, message) => {
+            endpoint.sendMessage(105, message);
+        }
     }
 
     Component.onCompleted: sendInit()
@@ -41,6 +71,21 @@ Rectangle {
         endpoint.sendMessage(100, broker.sendSimpleSignal("framebuffer-spy$getConfigString", ""));
     }
 
+    function calibrate(input) {
+        mainText = `Tap the center of each cross with the ${input}.`;
+        endpoint.sendMessage(102, input);
+    }
+
+    function answerRemoteInput(allow) {
+        endpoint.sendMessage(104, `${remoteInputRequest.id},${allow ? 1 : 0}`);
+        showNextRemoteInputRequest();
+    }
+
+    function showNextRemoteInputRequest() {
+        remoteInputRequest = queuedRemoteInputRequests.length > 0 ? queuedRemoteInputRequests[0] : null;
+        queuedRemoteInputRequests = queuedRemoteInputRequests.slice(1);
+    }
+
     signal close
     function unloading() {
         console.log("We're unloading!");
@@ -116,4 +161,193 @@ Rectangle {
             }
         }
     }
+
+    Rectangle {
+        id: calibrationButtons
+        width: 500 * 2 + 20
+        height: 80
+        anchors.horizontalCenter: parent.horizontalCenter
+        anchors.top: parent.verticalCenter
+        anchors.topMargin: 80
+
+        Rectangle {
+            width: 500
+            height: parent.height
+            anchors.left: parent.left
+            border.width: 2
+            border.color: "black"
+            anchors.verticalCenter: parent.verticalCenter
+            Text {
+                anchors.fill: parent
+                horizontalAlignment: Text.AlignHCenter
+                verticalAlignment: Text.AlignVCenter
+                text: "Calibrate pen"
+                font.pointSize: 24
+            }
+
+            MouseArea {
+                anchors.fill: parent
+                onClicked: () => {
+                    calibrate("pen");
+                }
+            }
+        }
+
+        Rectangle {
+            width: 500
+            height: parent.height
+            anchors.right: parent.right
+            border.width: 2
+            border.color: "black"
+            anchors.verticalCenter: parent.verticalCenter
+            Text {
+                anchors.fill: parent
+                horizontalAlignment: Text.AlignHCenter
+                verticalAlignment: Text.AlignVCenter
+                text: "Calibrate touch"
+                font.pointSize: 24
+            }
+
+            MouseArea {
+                anchors.fill: parent
+                onClicked: () => {
+                    calibrate("touch");
+                }
+            }
+        }
+    }
+
+    Rectangle {
+        anchors.fill: parent
+        visible: calibrationTarget !== null
+        color: "white"
+
+        // Swallows the taps, so that they don't press anything underneath.
+        MouseArea {
+            anchors.fill: parent
+        }
+
+        Text {
+            anchors.top: parent.top
+            anchors.topMargin: 10
+            width: parent.width
+            horizontalAlignment: Text.AlignHCenter
+            text: mainText
+            font.pointSize: 24
+        }
+
+        Item {
+            x: calibrationTarget ? calibrationTarget.x * parent.width : 0
+            y: calibrationTarget ? calibrationTarget.y * parent.height : 0
+
+            Rectangle {
+                x: -40
+                y: -2
+                width: 80
+                height: 4
+                color: "black"
+            }
+
+            Rectangle {
+                x: -2
+                y: -40
+                width: 4
+                height: 80
+                color: "black"
+            }
+        }
+
+        Rectangle {
+            width: 300
+            height: 80
+            anchors.horizontalCenter: parent.horizontalCenter
+            anchors.bottom: parent.bottom
+            anchors.bottomMargin: 200
+            border.width: 2
+            border.color: "black"
+            Text {
+                anchors.fill: parent
+                horizontalAlignment: Text.AlignHCenter
+                verticalAlignment: Text.AlignVCenter
+                text: "Cancel"
+                font.pointSize: 24
+            }
+
+            MouseArea {
+                anchors.fill: parent
+                onClicked: () => {
+                    endpoint.sendMessage(103, "");
+                    calibrationTarget = null;
+                    mainText = "Calibration cancelled";
+                }
+            }
+        }
+    }
+
+    Rectangle {
+        width: 1100
+        height: 400
+        anchors.centerIn: parent
+        visible: remoteInputRequest !== null
+        border.width: 4
+        border.color: "black"
+
+        Text {
+            anchors.top: parent.top
+            anchors.topMargin: 40
+            width: parent.width
+            horizontalAlignment: Text.AlignHCenter
+            wrapMode: Text.Wrap
+            text: remoteInputRequest ? `${remoteInputRequest.client} wants to control this device.` : ''
+            font.pointSize: 28
+        }
+
+        Rectangle {
+            width: 500
+            height: 80
+            anchors.left: parent.left
+            anchors.bottom: parent.bottom
+            anchors.margins: 40
+            border.width: 2
+            border.color: "black"
+            Text {
+                anchors.fill: parent
+                horizontalAlignment: Text.AlignHCenter
+                verticalAlignment: Text.AlignVCenter
+                text: "Deny"
+                font.pointSize: 24
+            }
+
+            MouseArea {
+                anchors.fill: parent
+                onClicked: () => {
+                    answerRemoteInput(false);
+                }
+            }
+        }
+
+        Rectangle {
+            width: 500
+            height: 80
+            anchors.right: parent.right
+            anchors.bottom: parent.bottom
+            anchors.margins: 40
+            border.width: 2
+            border.color: "black"
+            Text {
+                anchors.fill: parent
+                horizontalAlignment: Text.AlignHCenter
+                verticalAlignment: Text.AlignVCenter
+                text: "Allow"
+                font.pointSize: 24
+            }
+
+            MouseArea {
+                anchors.fill: parent
+                onClicked: () => {
+                    answerRemoteInput(true);
+                }
+            }
+        }
+    }
 }